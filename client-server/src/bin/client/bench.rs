@@ -0,0 +1,247 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hdrhistogram::Histogram;
+use serde::Serialize;
+
+use client_server::protocol::Response;
+use client_server::Client;
+
+use crate::mix::Generator;
+
+/// longest latency the histogram can record, in microseconds; anything slower is clamped to it
+const MAX_LATENCY_US: u64 = 60 * 1_000_000;
+
+/// When a benchmark run ends
+#[derive(Debug, Clone, Copy)]
+pub enum Stop {
+    /// after this long
+    After(Duration),
+
+    /// once this many commands have been sent
+    Count(usize),
+}
+
+/// settings for a benchmark run
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    /// when to stop sending commands
+    pub stop: Stop,
+
+    /// commands per second to send, summed over every worker; `None` sends as fast as possible
+    pub rate: Option<f64>,
+
+    /// number of threads sending commands at the same time
+    pub workers: usize,
+
+    /// print the results as json instead of a human-readable summary
+    pub json: bool,
+}
+
+/// latency percentiles, in microseconds
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub min: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+    pub max: u64,
+}
+
+/// Results of a benchmark run
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// seed the commands were generated from; pass it to --seed to send the same ones again
+    pub seed: u64,
+
+    /// commands that got a successful reply
+    pub completed: u64,
+
+    /// commands that failed, either with an error reply or without any reply at all
+    pub errors: u64,
+
+    /// wall-clock length of the run, in seconds
+    pub elapsed_secs: f64,
+
+    /// successful commands per second
+    pub throughput: f64,
+
+    /// latency of successful commands
+    pub latency_us: Latency,
+}
+
+/// What a single worker measured
+struct WorkerResult {
+    latencies: Histogram<u64>,
+    errors: u64,
+}
+
+/// send commands to the server with `config.workers` threads until `config.stop` says to stop,
+/// then print a report
+pub fn run(client: Client, config: BenchConfig, generator: Generator) {
+    let client = Arc::new(client);
+    let generator = Arc::new(generator);
+
+    // the workers share a single counter, which numbers the commands for the generator and, with
+    // a command count, makes the total come out right no matter how fast each worker goes
+    let sent = Arc::new(AtomicUsize::new(0));
+
+    // each worker gets an equal share of the target rate
+    let interval = config
+        .rate
+        .map(|rate| Duration::from_secs_f64(config.workers as f64 / rate));
+
+    let start = Instant::now();
+
+    let workers: Vec<_> = (0..config.workers)
+        .map(|_| {
+            let client = client.clone();
+            let generator = generator.clone();
+            let sent = sent.clone();
+
+            thread::spawn(move || worker(&client, &generator, config.stop, interval, start, &sent))
+        })
+        .collect();
+
+    // every worker records into a histogram of its own, so they never contend on a shared one;
+    // they're combined once the run is over
+    let mut latencies = new_histogram();
+    let mut errors = 0;
+
+    for handle in workers {
+        let result = handle.join().expect("benchmark worker panicked");
+
+        latencies
+            .add(&result.latencies)
+            .expect("Couldn't merge latency histograms");
+        errors += result.errors;
+    }
+
+    let report = report(generator.seed(), &latencies, errors, start.elapsed());
+
+    if config.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("Couldn't serialize report")
+        );
+    } else {
+        print_report(&report);
+    }
+}
+
+/// body of each worker thread: send commands, one at a time, timing each one
+fn worker(
+    client: &Client,
+    generator: &Generator,
+    stop: Stop,
+    interval: Option<Duration>,
+    start: Instant,
+    sent: &AtomicUsize,
+) -> WorkerResult {
+    let mut latencies = new_histogram();
+    let mut errors = 0;
+
+    // when the next command is due to be sent; only used when there's a target rate
+    let mut next = start;
+
+    loop {
+        let index = sent.fetch_add(1, Ordering::Relaxed);
+
+        let keep_going = match stop {
+            Stop::After(duration) => start.elapsed() < duration,
+            Stop::Count(count) => index < count,
+        };
+
+        if !keep_going {
+            break;
+        }
+
+        // with a target rate, commands are sent on a fixed schedule, and latency is measured
+        // from when the command *should* have gone out. If the server stalls, the commands that
+        // pile up behind the stall are charged for the time they spent waiting. Measuring from
+        // when they actually went out instead hides the stall almost entirely, a mistake known
+        // as coordinated omission
+        let began = match interval {
+            Some(interval) => {
+                let now = Instant::now();
+
+                if next > now {
+                    thread::sleep(next - now);
+                }
+
+                let scheduled = next;
+                next += interval;
+                scheduled
+            }
+            None => Instant::now(),
+        };
+
+        let cmd = generator.command(index);
+
+        match client.execute(&cmd) {
+            Ok(Response::Error { .. }) | Err(_) => errors += 1,
+            Ok(_) => {
+                let micros = began.elapsed().as_micros() as u64;
+
+                latencies.saturating_record(micros.clamp(1, MAX_LATENCY_US));
+            }
+        }
+    }
+
+    WorkerResult { latencies, errors }
+}
+
+/// an empty histogram covering 1µs to a minute, accurate to 3 significant digits
+///
+/// an HDR histogram keeps a bucket per range of values, with the ranges getting wider as the
+/// values grow. That keeps the relative error the same at every scale while using a fixed amount
+/// of memory, no matter how many values are recorded
+fn new_histogram() -> Histogram<u64> {
+    Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).expect("Couldn't create latency histogram")
+}
+
+/// summarize a finished run
+fn report(seed: u64, latencies: &Histogram<u64>, errors: u64, elapsed: Duration) -> Report {
+    let completed = latencies.len();
+    let elapsed_secs = elapsed.as_secs_f64();
+
+    Report {
+        seed,
+        completed,
+        errors,
+        elapsed_secs,
+        throughput: completed as f64 / elapsed_secs,
+        latency_us: Latency {
+            min: latencies.min(),
+            mean: latencies.mean(),
+            p50: latencies.value_at_quantile(0.5),
+            p90: latencies.value_at_quantile(0.9),
+            p99: latencies.value_at_quantile(0.99),
+            p999: latencies.value_at_quantile(0.999),
+            max: latencies.max(),
+        },
+    }
+}
+
+/// print `report` for a human to read
+fn print_report(report: &Report) {
+    let latency = &report.latency_us;
+
+    println!(
+        "{} commands in {:.2}s ({} errors, seed {})",
+        report.completed, report.elapsed_secs, report.errors, report.seed
+    );
+    println!("throughput: {:.1} commands/s", report.throughput);
+    println!("latency (µs):");
+    println!("  min   {:>10}", latency.min);
+    println!("  mean  {:>10.1}", latency.mean);
+    println!("  p50   {:>10}", latency.p50);
+    println!("  p90   {:>10}", latency.p90);
+    println!("  p99   {:>10}", latency.p99);
+    println!("  p99.9 {:>10}", latency.p999);
+    println!("  max   {:>10}", latency.max);
+}
@@ -0,0 +1,364 @@
+use std::time::Duration;
+
+use clap::{App, Arg, SubCommand}; // command line parsing
+
+// a prelude is a rust convention that groups the most commonly used parts of a library into one
+// convenient location. The syntax below is a glob import of the entire prelude.
+use pyo3::prelude::*; // foreign function interface for python
+use rayon::prelude::*; // parallel execution // rust/python
+
+use client_server::client::{ClientConfig, Target};
+use client_server::Client;
+
+mod bench;
+mod mix;
+mod repl;
+
+use bench::{BenchConfig, Stop};
+use mix::{CommandMix, Generator};
+
+/// What the client does once it's connected
+enum Mode {
+    /// send `-n` random commands in parallel, printing each reply
+    Fire,
+
+    /// measure the server's latency and throughput
+    Bench(BenchConfig),
+
+    /// read commands typed at a prompt and send them one at a time
+    Repl,
+}
+
+/// command line arguments for the client
+struct Args {
+    /// number of commands to send
+    num_connections: usize,
+
+    /// what to do
+    mode: Mode,
+
+    /// where the server is listening
+    target: Target,
+
+    /// timeouts, pooling and retry settings for the connection to the server
+    client: ClientConfig,
+
+    /// produces the commands to send
+    generator: Generator,
+}
+
+/// parse command line arguments `-n`, `--target`, the timeouts and retries, and return their
+/// values as `Args`
+fn get_args() -> Args {
+    // define a new application that accepts our arguments using the clap crate
+    let app = App::new("client")
+        .arg(
+            Arg::with_name("num_connections")
+                .short("n")
+                .help("Number of commands to send (default: 30)")
+                .takes_value(true)
+                .global(true)
+                .default_value("30"),
+        )
+        .arg(
+            Arg::with_name("target")
+                .long("target")
+                .short("t")
+                .help("Server address as host:port; wrap IPv6 hosts in brackets, e.g. [::1]:4444")
+                .takes_value(true)
+                .global(true)
+                .default_value("127.0.0.1:4444"),
+        )
+        .arg(
+            Arg::with_name("unix_socket")
+                .long("unix-socket")
+                .help("Connect over this unix socket instead of --target, to a server on this host")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("connect_timeout")
+                .long("connect-timeout")
+                .help("Milliseconds to wait for a connection to the server")
+                .takes_value(true)
+                .global(true)
+                .default_value("3000"),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .help("Milliseconds to wait for the server to reply to a command")
+                .takes_value(true)
+                .global(true)
+                .default_value("5000"),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("Times to retry a command that failed in a way that's safe to retry")
+                .takes_value(true)
+                .global(true)
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("mix")
+                .long("mix")
+                .short("m")
+                .help("Weighted mix of commands to send, e.g. fetch=70,increment=30")
+                .takes_value(true)
+                .global(true)
+                .default_value("ping=1,increment=1,decrement=1,fetch=1"),
+        )
+        .arg(
+            Arg::with_name("values")
+                .long("values")
+                .help("Range that increment and decrement amounts are drawn from, end excluded")
+                .takes_value(true)
+                .global(true)
+                .allow_hyphen_values(true)
+                .default_value("0..1000"),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("seed")
+                .short("s")
+                .help("Seed for the command mix; the same seed sends the same commands again")
+                .takes_value(true)
+                .global(true),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Measure latency and throughput instead of printing every reply")
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .short("d")
+                        .help("Seconds to run for; without it, the run stops after -n commands")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("rate")
+                        .long("rate")
+                        .short("r")
+                        .help("Commands per second to send, across all workers (default: no limit)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("workers")
+                        .long("workers")
+                        .short("w")
+                        .help("Number of threads sending commands at the same time")
+                        .takes_value(true)
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the results as json"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("repl")
+                .about("Type commands like 'inc 5' or 'fetch' and see the server's replies"),
+        );
+
+    // perform the actual parsing
+    let matches = app.get_matches();
+
+    // we provide a default to each Arg; these will always have a value/can't fail
+    let conns = matches.value_of("num_connections").unwrap();
+
+    // try to parse &str as the variable's specified type (usize)
+    let num_connections: usize = conns.parse().expect("Couldn't cast -n value to usize");
+
+    // the target is resolved when connecting, which accepts hostnames as well as IP addresses
+    // and reports anything it can't resolve
+    let target = match matches.value_of("unix_socket") {
+        #[cfg(unix)]
+        Some(path) => Target::Unix(path.into()),
+        #[cfg(not(unix))]
+        Some(_) => panic!("--unix-socket is only supported on unix"),
+        None => Target::Tcp(matches.value_of("target").unwrap().to_string()),
+    };
+
+    let connect_timeout = matches
+        .value_of("connect_timeout")
+        .unwrap()
+        .parse()
+        .map(Duration::from_millis)
+        .expect("Couldn't cast --connect-timeout value to u64");
+
+    let timeout = matches
+        .value_of("timeout")
+        .unwrap()
+        .parse()
+        .map(Duration::from_millis)
+        .expect("Couldn't cast --timeout value to u64");
+
+    let max_retries = matches
+        .value_of("retries")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --retries value to u32");
+
+    let values = mix::parse_range(matches.value_of("values").unwrap())
+        .unwrap_or_else(|e| panic!("Couldn't parse --values: {}", e));
+
+    let mix = CommandMix::new(matches.value_of("mix").unwrap(), values)
+        .unwrap_or_else(|e| panic!("Couldn't parse --mix: {}", e));
+
+    // without a seed, pick one at random. Either way, it's reported, so any run can be replayed
+    let seed = match matches.value_of("seed") {
+        Some(seed) => seed.parse().expect("Couldn't cast --seed value to u64"),
+        None => rand::random(),
+    };
+
+    let generator = Generator::new(mix, seed);
+
+    // subcommand() returns the name of the subcommand that was given, along with its arguments
+    let mode = match matches.subcommand() {
+        ("bench", Some(bench)) => {
+            // -n doubles as the command count, unless a duration was given
+            let stop = match bench.value_of("duration") {
+                Some(secs) => {
+                    let secs: f64 = secs.parse().expect("Couldn't cast --duration value to f64");
+
+                    // Duration::from_secs_f64 panics on anything negative or infinite
+                    assert!(
+                        secs >= 0.0 && secs.is_finite(),
+                        "--duration must be 0 or more seconds"
+                    );
+                    Stop::After(Duration::from_secs_f64(secs))
+                }
+                None => Stop::Count(num_connections),
+            };
+
+            let rate = bench.value_of("rate").map(|rate| {
+                let rate: f64 = rate.parse().expect("Couldn't cast --rate value to f64");
+
+                // the rate is turned into the time between one worker's commands, which only
+                // exists for a rate above 0 (this is false for NaN, too)
+                assert!(
+                    rate > 0.0 && rate.is_finite(),
+                    "--rate must be above 0; leave it out to send as fast as possible"
+                );
+                rate
+            });
+
+            let workers = bench
+                .value_of("workers")
+                .unwrap()
+                .parse()
+                .expect("Couldn't cast --workers value to usize");
+
+            // with no workers, nothing would ever be sent
+            assert!(workers > 0, "--workers must be at least 1");
+
+            Mode::Bench(BenchConfig {
+                stop,
+                rate,
+                workers,
+                json: bench.is_present("json"),
+            })
+        }
+        ("repl", _) => Mode::Repl,
+        _ => Mode::Fire,
+    };
+
+    // keep one idle connection per thread, so every thread can reuse a connection
+    let pool_size = match &mode {
+        Mode::Bench(config) => config.workers,
+        Mode::Fire => rayon::current_num_threads(),
+        // commands are typed one at a time, so a single connection is all the repl ever needs
+        Mode::Repl => 1,
+    };
+
+    let client = ClientConfig {
+        connect_timeout,
+        timeout,
+        max_retries,
+        pool_size,
+        ..ClientConfig::default()
+    };
+
+    Args {
+        num_connections,
+        mode,
+        target,
+        client,
+        generator,
+    }
+}
+
+/// given a unique id, send the matching Command from `generator` to the companion server using
+/// `client`
+///
+/// `client` takes care of connecting, reusing connections between calls, and retrying commands
+/// that failed in a way that's safe to retry
+fn spawn_connection(id: usize, client: &Client, generator: &Generator) {
+    let action = generator.command(id);
+
+    // send the command and wait for the reply
+    match client.execute(&action) {
+        Ok(response) => println!("[{:7}] sent {:?}; received {}", id, action, response),
+        Err(e) => eprintln!("[{:7}] sent {:?}; {}", id, action, e),
+    }
+}
+
+fn main() {
+    // parse -n, --target and friends from the command line
+    let args = get_args();
+    let num_conns = args.num_connections;
+
+    // a single Client is shared by every thread below; it hands each command a connection from
+    // its pool
+    let client = Client::with_config(args.target, args.client);
+    let generator = args.generator;
+
+    // a benchmark prints its own report, and nothing else, and the repl owns the terminal; both
+    // stay out of rich's way
+    match args.mode {
+        Mode::Bench(config) => return bench::run(client, config, generator),
+        Mode::Repl => return repl::run(client),
+        Mode::Fire => {}
+    }
+
+    // acquire CPython's infamous Global Interpreter Lock, which prevents several threads
+    // from executing Python bytecode in parallel
+    let gil = Python::acquire_gil();
+    let python = gil.python();
+
+    // import the `rich` python library; rich needs to be installed in our current python env
+    let rich = python.import("rich").expect("Couldn't import rich");
+
+    // get a reference to rich.print(), so we can use rich's markup language and format output
+    let rich_print = rich.getattr("print").expect("Couldn't get print function");
+
+    // call rich.print
+    rich_print
+        .call(
+            (format!(
+                "[green][+][/green] beginning parallel execution (seed: {})",
+                generator.seed()
+            ),),
+            None,
+        )
+        .expect("Couldn't print with rich");
+
+    // temporarily release the GIL
+    python.allow_threads(move || {
+        // use the rayon library for incredibly simple parallel execution with a high-level iterator
+        // style interface. `i` in the expression below is simply the values from 0 to `num_conns`
+        // being passed to the `for_each` block. Every thread borrows the same `client`; its
+        // connection pool lets each thread reuse a connection instead of opening a new one per
+        // command
+        (0..num_conns).into_par_iter().for_each(|i| {
+            spawn_connection(i, &client, &generator);
+        });
+    });
+    // GIL reacquired at this point
+
+    rich_print
+        .call(("[green][+][/green] parallel execution complete",), None)
+        .expect("Couldn't print with rich");
+}
@@ -0,0 +1,239 @@
+use std::ops::Range;
+use std::str::FromStr;
+
+use rand::distributions::{Distribution, Uniform, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use client_server::protocol::{Command, DEFAULT_COUNTER};
+
+/// The kinds of command the client knows how to generate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Ping,
+    Increment,
+    Decrement,
+    Fetch,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "ping" => Ok(Kind::Ping),
+            "increment" | "inc" => Ok(Kind::Increment),
+            "decrement" | "dec" => Ok(Kind::Decrement),
+            "fetch" => Ok(Kind::Fetch),
+            _ => Err(format!(
+                "unknown command '{}', expected one of ping, increment, decrement, fetch",
+                s
+            )),
+        }
+    }
+}
+
+/// A weighted random mix of commands, e.g. 70% Fetch and 30% Increment
+///
+/// `CommandMix` implements rand's `Distribution` trait, which is what lets us write
+/// `rng.sample(&mix)` and get back a `Command`, the same way `rng.gen_range(0..10)` gets back a
+/// number. The distribution decides *what* to produce; the rng passed in supplies the
+/// randomness. Handing it a seeded rng makes the output reproducible.
+#[derive(Debug, Clone)]
+pub struct CommandMix {
+    /// the command kinds that can be picked, lined up with `weights`
+    kinds: Vec<Kind>,
+
+    /// picks an index into `kinds`, with probability proportional to each kind's weight
+    weights: WeightedIndex<u32>,
+
+    /// picks the amount used by Increment and Decrement
+    values: Uniform<i64>,
+}
+
+impl CommandMix {
+    /// build a mix from a spec like `fetch=70,increment=30`; amounts are drawn from `values`
+    pub fn new(spec: &str, values: Range<i64>) -> Result<Self, String> {
+        let mut kinds = Vec::new();
+        let mut weights = Vec::new();
+
+        for entry in spec.split(',') {
+            let (kind, weight) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected command=weight, got '{}'", entry))?;
+
+            kinds.push(kind.parse()?);
+            weights.push(
+                weight
+                    .trim()
+                    .parse::<u32>()
+                    .map_err(|e| format!("bad weight '{}': {}", weight, e))?,
+            );
+        }
+
+        // WeightedIndex refuses an empty list, or one where every weight is 0
+        let weights = WeightedIndex::new(weights).map_err(|e| format!("bad mix: {}", e))?;
+
+        if values.is_empty() {
+            return Err(format!("empty value range {:?}", values));
+        }
+
+        Ok(Self {
+            kinds,
+            weights,
+            values: Uniform::from(values),
+        })
+    }
+}
+
+impl Distribution<Command> for CommandMix {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Command {
+        // first pick which kind of command, then fill in its arguments
+        let kind = self.kinds[self.weights.sample(rng)];
+
+        match kind {
+            Kind::Ping => Command::Ping,
+            Kind::Increment => {
+                Command::Increment(DEFAULT_COUNTER.to_string(), self.values.sample(rng))
+            }
+            Kind::Decrement => {
+                Command::Decrement(DEFAULT_COUNTER.to_string(), self.values.sample(rng))
+            }
+            Kind::Fetch => Command::Fetch(DEFAULT_COUNTER.to_string()),
+        }
+    }
+}
+
+/// Generates a reproducible sequence of commands from a `CommandMix` and a seed
+///
+/// Commands are sent from many threads at once, in whatever order the threads happen to run,
+/// so a single seeded rng shared between them wouldn't give the same commands twice. Instead,
+/// command number `i` always comes from its own rng, seeded with `seed + i`. The same seed
+/// therefore produces the same command for the same number, no matter which thread sends it.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    mix: CommandMix,
+    seed: u64,
+}
+
+impl Generator {
+    pub fn new(mix: CommandMix, seed: u64) -> Self {
+        Self { mix, seed }
+    }
+
+    /// the seed this generator was created with; pass it to --seed to replay a run
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// the command with the given sequence number
+    pub fn command(&self, index: usize) -> Command {
+        // seed_from_u64 scrambles the seed before using it, so neighbouring seeds still give
+        // unrelated sequences
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(index as u64));
+
+        rng.sample(&self.mix)
+    }
+}
+
+/// parse a range of values written the way rust writes them, e.g. `0..1000` (end excluded)
+pub fn parse_range(s: &str) -> Result<Range<i64>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected a range like 0..1000, got '{}'", s))?;
+
+    let start = start
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad range start '{}': {}", start, e))?;
+    let end = end
+        .trim()
+        .parse()
+        .map_err(|e| format!("bad range end '{}': {}", end, e))?;
+
+    // there'd be no values to pick from; 5..5 and 9..1 are both empty
+    if start >= end {
+        return Err(format!(
+            "empty range '{}', the end must be after the start",
+            s
+        ));
+    }
+
+    Ok(start..end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mix(spec: &str) -> Result<CommandMix, String> {
+        CommandMix::new(spec, 0..1000)
+    }
+
+    /// the commands `generator` makes for each of `indices`, as shown by Debug, since Command
+    /// can't be compared with ==
+    fn commands(generator: &Generator, indices: impl Iterator<Item = usize>) -> Vec<String> {
+        indices
+            .map(|i| format!("{:?}", generator.command(i)))
+            .collect()
+    }
+
+    #[test]
+    fn the_same_seed_gives_the_same_commands() {
+        let mix = mix("ping=1,increment=2,decrement=2,fetch=1").unwrap();
+        let generator = Generator::new(mix.clone(), 42);
+        let expected = commands(&generator, 0..100);
+
+        assert_eq!(commands(&Generator::new(mix.clone(), 42), 0..100), expected);
+
+        // the command for a number doesn't depend on the order numbers are asked for
+        let mut backwards = commands(&generator, (0..100).rev());
+        backwards.reverse();
+        assert_eq!(backwards, expected);
+
+        assert_ne!(commands(&Generator::new(mix, 43), 0..100), expected);
+    }
+
+    #[test]
+    fn only_commands_in_the_mix_are_generated() {
+        let generator = Generator::new(mix("fetch=0,inc=1").unwrap(), 7);
+
+        for i in 0..100 {
+            match generator.command(i) {
+                Command::Increment(name, amount) => {
+                    assert_eq!(name, DEFAULT_COUNTER);
+                    assert!((0..1000).contains(&amount));
+                }
+                other => panic!("expected an increment, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_bad_mixes() {
+        for spec in &[
+            "",
+            "fetch",
+            "fetch=0",
+            "fetch=0,ping=0",
+            "fetch=-1",
+            "fetch=lots",
+            "shutdown=1",
+            "fetch=1,list=1",
+        ] {
+            assert!(mix(spec).is_err(), "{}", spec);
+        }
+
+        assert!(CommandMix::new("fetch=1", 5..5).is_err());
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(parse_range("0..1000"), Ok(0..1000));
+        assert_eq!(parse_range(" -5 .. 5 "), Ok(-5..5));
+
+        for range in &["5..5", "9..1", "0-10", "..10", "a..b"] {
+            assert!(parse_range(range).is_err(), "{}", range);
+        }
+    }
+}
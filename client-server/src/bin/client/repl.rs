@@ -0,0 +1,144 @@
+use std::env;
+use std::path::PathBuf;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use client_server::protocol::{Command, Response};
+use client_server::syntax::parse_command;
+use client_server::Client;
+
+/// name of the file, in the user's home directory, that keeps history between sessions
+const HISTORY_FILE: &str = ".client_server_history";
+
+const HELP: &str = "\
+commands (the counter name is optional and defaults to 'default'):
+  ping                      check that the server is responding
+  inc [name] <amount>       add to a counter (also: incr, increment)
+  dec [name] <amount>       subtract from a counter (also: decr, decrement)
+  set [name] <value>        set a counter to a value
+  fetch [name]              get a counter's value (also: get)
+  list                      list every counter's name
+  del <name>                delete a counter (also: delete)
+  help                      show this message
+  quit                      leave the repl (also: exit, ctrl+d)";
+
+/// What a line typed at the prompt asks for
+enum Input {
+    /// send this to the server
+    Send(Command),
+
+    /// print the help text
+    Help,
+
+    /// leave the repl
+    Quit,
+
+    /// nothing but whitespace
+    Empty,
+}
+
+/// read commands from the terminal, send each one to the server and print the reply, until the
+/// user quits
+///
+/// `client` keeps its connection open between commands, so the whole session runs over a single
+/// connection unless the server hangs up on us
+pub fn run(client: Client) {
+    // rustyline provides the line editing: arrow keys, ctrl+r history search, and so on
+    let mut editor = Editor::<()>::new();
+    let history = history_path();
+
+    if let Some(path) = &history {
+        // there's no history file the very first time, which is fine
+        let _ = editor.load_history(path);
+    }
+
+    println!("type 'help' for a list of commands");
+
+    loop {
+        let line = match editor.readline("counter> ") {
+            Ok(line) => line,
+            // ctrl+c clears the current line, like a shell does
+            Err(ReadlineError::Interrupted) => continue,
+            // ctrl+d on an empty line
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("couldn't read input: {}", e);
+                break;
+            }
+        };
+
+        editor.add_history_entry(line.as_str());
+
+        let cmd = match parse(&line) {
+            Ok(Input::Send(cmd)) => cmd,
+            Ok(Input::Help) => {
+                println!("{}", HELP);
+                continue;
+            }
+            Ok(Input::Quit) => break,
+            Ok(Input::Empty) => continue,
+            Err(e) => {
+                println!("(error) {}", e);
+                continue;
+            }
+        };
+
+        match client.execute(&cmd) {
+            Ok(response) => println!("{}", pretty(&response)),
+            Err(e) => println!("(error) {}", e),
+        }
+    }
+
+    if let Some(path) = &history {
+        if let Err(e) = editor.save_history(path) {
+            eprintln!("couldn't save history to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// turn a line typed at the prompt into an `Input`
+///
+/// besides help and quit, this is the syntax the server's text protocol speaks too, see
+/// `client_server::syntax::parse_command`
+fn parse(line: &str) -> Result<Input, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let (verb, args) = match words.split_first() {
+        Some((verb, args)) => (verb.to_lowercase(), args),
+        None => return Ok(Input::Empty),
+    };
+
+    match verb.as_str() {
+        "help" | "?" => Ok(Input::Help),
+        "quit" | "exit" => Ok(Input::Quit),
+        _ => match parse_command(&verb, args) {
+            Some(cmd) => cmd.map(Input::Send),
+            None => Err(format!("unknown command '{}'; try 'help'", verb)),
+        },
+    }
+}
+
+/// format a `Response` for the terminal, in the style of redis-cli
+fn pretty(response: &Response) -> String {
+    match response {
+        Response::Pong => "PONG".to_string(),
+        Response::Ok => "OK".to_string(),
+        Response::Value(value) => format!("(integer) {}", value),
+        Response::Names(names) if names.is_empty() => "(empty list)".to_string(),
+        Response::Names(names) => names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("{}) {}", i + 1, name))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Response::Error { code, message } => format!("(error) {:?}: {}", code, message),
+        // everything else already reads well enough as is
+        other => other.to_string(),
+    }
+}
+
+/// where history is kept between sessions; `None` when there's no home directory to put it in
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
@@ -0,0 +1,221 @@
+use std::io;
+use std::sync::Arc;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::runtime::Builder;
+use tokio::task;
+use tokio::time;
+
+use client_server::protocol::{Message, ProtocolError};
+
+use crate::shutdown::{shutting_down, POLL_INTERVAL};
+use crate::text;
+use crate::timeouts::{write_with_timeout, AsyncTimedReader};
+use crate::transport::Listener;
+use crate::{error_response, Shared};
+
+/// Accept connections until a shutdown is requested, serving each one as a task on a tokio
+/// runtime
+///
+/// A task is much cheaper than an OS thread (a few hundred bytes vs. a few megabytes of stack),
+/// so tens of thousands of idle or slow connections don't exhaust the machine the way they do
+/// with the threaded server.
+pub fn serve(listeners: Vec<Listener>, shared: Arc<Shared>) {
+    // the multi-threaded runtime spreads tasks across one worker thread per cpu core. `blocking`
+    // depends on it; block_in_place panics on the single-threaded runtime
+    let runtime = Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Couldn't start tokio runtime");
+
+    // block_on runs the given future to completion on the runtime. each listener gets its own
+    // accept task, and they all return once a shutdown is requested
+    let accept_ref = shared.clone();
+
+    runtime.block_on(async move {
+        let accept_tasks: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(accept_loop(listener, accept_ref.clone())))
+            .collect();
+
+        for task in accept_tasks {
+            let _ = task.await;
+        }
+    });
+
+    // connection tasks keep running on the runtime's worker threads while we wait here. Once
+    // `runtime` is dropped at the end of this function, any task that's still around (e.g. an
+    // idle connection waiting for its next message) is cancelled
+    shared
+        .shutdown
+        .wait_for_drain(shared.config.shutdown_timeout);
+}
+
+/// tokio's counterpart of `Listener`
+enum AsyncListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// a connection accepted by an `AsyncListener`
+enum AsyncStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl AsyncListener {
+    /// take ownership of an already bound std listener
+    fn from_std(listener: Listener) -> io::Result<Self> {
+        // tokio expects to be the only thing driving the socket, which means it must not block
+        listener.set_nonblocking(true)?;
+
+        match listener {
+            Listener::Tcp(listener) => TcpListener::from_std(listener).map(AsyncListener::Tcp),
+            #[cfg(unix)]
+            Listener::Unix(listener) => UnixListener::from_std(listener).map(AsyncListener::Unix),
+        }
+    }
+
+    async fn accept(&self) -> io::Result<AsyncStream> {
+        match self {
+            AsyncListener::Tcp(listener) => {
+                let (stream, _addr) = listener.accept().await?;
+                Ok(AsyncStream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            AsyncListener::Unix(listener) => {
+                let (stream, _addr) = listener.accept().await?;
+                Ok(AsyncStream::Unix(stream))
+            }
+        }
+    }
+}
+
+/// take ownership of an already bound std listener and spawn a task per accepted connection
+async fn accept_loop(listener: Listener, shared: Arc<Shared>) {
+    let listener = AsyncListener::from_std(listener).expect("Couldn't hand listener to tokio");
+
+    while !shared.shutdown.is_requested() {
+        // accept() only finishes when a client connects; putting a time limit on it gives us a
+        // chance to check for a shutdown every so often. Giving up on an accept is harmless, a
+        // client that shows up in the meantime waits in the listen backlog for the next attempt
+        let stream = match time::timeout(POLL_INTERVAL, listener.accept()).await {
+            Err(_elapsed) => continue,
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                // a failed accept (e.g. too many open files) only affects that one connection;
+                // keep serving everyone else
+                eprintln!("Couldn't accept connection: {}", e);
+                continue;
+            }
+        };
+
+        let id = shared.next_connection_id();
+        let per_task_ref = shared.clone();
+
+        // tokio::spawn is the async equivalent of thread::spawn; the task runs concurrently with
+        // this loop on whichever worker thread is free
+        match stream {
+            AsyncStream::Tcp(stream) => {
+                tokio::spawn(handle_tcp_connection(id, stream, per_task_ref))
+            }
+            #[cfg(unix)]
+            AsyncStream::Unix(stream) => tokio::spawn(handle_connection(id, stream, per_task_ref)),
+        };
+    }
+}
+
+/// run `f`, which may block the thread it's on, from inside a task
+///
+/// executing a command takes the store's std locks, copies the whole store for a batch, and
+/// with `--fsync always` waits on the disk. A task that blocks its worker thread like that
+/// stalls every other task queued on the same worker. block_in_place tells tokio to hand those
+/// tasks to another thread first, so only this task waits; unlike spawn_blocking, `f` can
+/// borrow from the task
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    task::block_in_place(f)
+}
+
+/// serve a tcp connection, which may speak the text protocol rather than the framed one
+async fn handle_tcp_connection(id: usize, stream: TcpStream, shared: Arc<Shared>) {
+    // see threaded::handle_connection
+    match text::sniff_async(&stream, shared.config.timeouts.idle).await {
+        Ok(true) => text::handle_async_connection(id, stream, shared).await,
+        Ok(false) => handle_connection(id, stream, shared).await,
+        Err(e) => eprintln!("[{:7}] {}", id, e),
+    }
+}
+
+/// async counterpart of `threaded::handle_connection`; serves messages until the client hangs up
+///
+/// works the same for any stream tokio can read from and write to, e.g. a tcp or unix socket
+async fn handle_connection<S>(id: usize, mut stream: S, shared: Arc<Shared>)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let max_frame_size = shared.config.max_frame_size;
+    let timeouts = shared.config.timeouts;
+
+    loop {
+        // see threaded::handle_connection; the reader gives up on quiet or slow clients
+        let mut reader = AsyncTimedReader::new(&mut stream, timeouts);
+
+        let msg = match Message::from_async_stream_with_limit(&mut reader, max_frame_size).await {
+            Ok(msg) => msg,
+            Err(ProtocolError::Eof) => return,
+            Err(e) => {
+                let response = match error_response(&e) {
+                    Some(response) => response,
+                    None => {
+                        eprintln!("[{:7}] {}", id, e);
+                        return;
+                    }
+                };
+
+                println!("[{:7}] {}; replying with {}", id, e, response);
+
+                if let Err(e) = write_with_timeout(&response, &mut stream, timeouts).await {
+                    eprintln!("[{:7}] couldn't send reply: {}", id, e);
+                    return;
+                }
+
+                // see threaded::handle_connection for why an oversized frame ends the session
+                if let ProtocolError::Oversize { .. } = e {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // see threaded::handle_connection; `_request` keeps the request in flight until the
+        // reply has been sent
+        let _request = match shared.shutdown.begin_request() {
+            Some(request) => request,
+            None => {
+                let response = shutting_down();
+                println!("[{:7}] received {}; replying with {}", id, msg, response);
+
+                let _ = write_with_timeout(&response, &mut stream, timeouts).await;
+                return;
+            }
+        };
+
+        let response = blocking(|| shared.dispatch(&msg));
+
+        println!("[{:7}] received {}; replying with {}", id, msg, response);
+
+        if let Err(e) = write_with_timeout(&response, &mut stream, timeouts).await {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        if shared.shutdown.is_requested() {
+            return;
+        }
+    }
+}
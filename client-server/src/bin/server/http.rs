@@ -0,0 +1,733 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::sync::Arc;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use client_server::protocol::{Command, ErrorCode, Message, Response, DEFAULT_COUNTER};
+
+use crate::resp::read_line;
+use crate::shutdown::shutting_down;
+use crate::timeouts::TimedReader;
+use crate::transport::Stream;
+use crate::{error_response, Shared};
+
+/// most headers a request may have; nothing we serve needs more than a handful
+const MAX_HEADERS: usize = 64;
+
+/// A request, as far as we care about it
+struct Request {
+    method: String,
+
+    /// the path part of the request target, without any query string
+    path: String,
+
+    body: Vec<u8>,
+
+    /// true if the client asked us to close the connection after replying
+    close: bool,
+}
+
+/// A reply; every body we send is json
+struct Reply {
+    status: u16,
+    body: Value,
+}
+
+impl Reply {
+    fn ok(body: Value) -> Self {
+        Self { status: 200, body }
+    }
+
+    /// an error reply, shaped like the ones built from an error `Response`
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        let message = message.into();
+
+        Self {
+            status,
+            body: json!({ "error": { "message": message } }),
+        }
+    }
+
+    /// translate an error `Response` into a reply with a matching status code
+    fn from_error(code: ErrorCode, message: String) -> Self {
+        Self {
+            status: status(code),
+            body: json!({ "error": { "code": code, "message": message } }),
+        }
+    }
+}
+
+/// body of POST /counter/increment and /counter/decrement; `{"amount": 5}`
+#[derive(Deserialize)]
+struct AmountBody {
+    #[serde(default = "one")]
+    amount: i64,
+}
+
+/// body of PUT /counter; `{"value": 5}`
+#[derive(Deserialize)]
+struct ValueBody {
+    value: i64,
+}
+
+fn one() -> i64 {
+    1
+}
+
+/// Serve a connection from an HTTP client (a dashboard, curl in a CI job, ...)
+///
+/// Every endpoint talks json and maps onto one of the server's own `Command`s, run exactly like
+/// one that arrived over the framed protocol, against the same counters:
+///
+/// | endpoint                               | body               | command            |
+/// |----------------------------------------|--------------------|--------------------|
+/// | `GET` or `POST /ping`                  |                    | `Ping`             |
+/// | `GET /counter[/<name>]`                |                    | `Fetch`            |
+/// | `PUT /counter[/<name>]`                | `{"value": 5}`     | `Set`              |
+/// | `POST /counter[/<name>]/increment`     | `{"amount": 5}`    | `IncrementAndGet`  |
+/// | `POST /counter[/<name>]/decrement`     | `{"amount": 5}`    | `DecrementAndGet`  |
+/// | `DELETE /counter/<name>`               |                    | `Delete`           |
+/// | `GET /counters`                        |                    | `List`             |
+/// | `POST /command`                        | a json `Message`   | whatever it holds  |
+///
+/// Without a name, the default counter is used; names may be percent-encoded, so `/counter/a%20b`
+/// is the counter `a b`. The amount is optional and defaults to 1.
+/// `POST /command` takes the same json a framed message carries, and replies with the json of
+/// the `Response`, for anything the other endpoints don't cover (batches, compare-and-swap...).
+///
+/// Connections are kept open between requests unless the client asks otherwise, as HTTP/1.1
+/// allows. Chunked request bodies aren't supported; send a Content-Length.
+pub fn handle_connection(id: usize, stream: Stream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+
+    if let Err(e) = stream.set_write_timeout(timeouts.write) {
+        eprintln!("[{:7}] couldn't set write timeout: {}", id, e);
+        return;
+    }
+
+    // see resp::handle_connection
+    let mut reader = BufReader::new(TimedReader::new(&stream, timeouts));
+
+    loop {
+        reader.get_mut().next_message();
+
+        let request = match read_request(&mut reader, shared.config.max_frame_size) {
+            Ok(Some(Ok(request))) => request,
+            // there's no telling where the next request starts after a bad one, so hang up
+            Ok(Some(Err(reply))) => {
+                println!("[{:7}] bad request; replying with {}", id, reply.status);
+                let _ = send(&stream, &reply, true);
+                return;
+            }
+            // the client hung up between requests
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("[{:7}] {}", id, e);
+                return;
+            }
+        };
+
+        // see threaded::handle_connection
+        let _request = match shared.shutdown.begin_request() {
+            Some(request) => request,
+            None => {
+                let _ = send(&stream, &error_reply(shutting_down()), true);
+                return;
+            }
+        };
+
+        let reply = route(&request, &shared);
+        println!(
+            "[{:7}] received {} {}; replying with {}",
+            id, request.method, request.path, reply.status
+        );
+
+        // the reply is out; rather than wait for another request we'd only refuse, hang up
+        let close = request.close || shared.shutdown.is_requested();
+
+        if let Err(e) = send(&stream, &reply, close) {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        if close {
+            return;
+        }
+    }
+}
+
+/// Read a single request: the request line, headers, and body
+///
+/// returns `None` when the client closed the connection before starting a new request, and
+/// `Some(Err(reply))` for a request we won't serve
+fn read_request<R: BufRead>(
+    reader: &mut R,
+    max_size: usize,
+) -> io::Result<Option<Result<Request, Reply>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    // e.g. "POST /counter/increment HTTP/1.1"
+    let words: Vec<&str> = line.split(' ').collect();
+
+    let (method, target, version) = match words.as_slice() {
+        [method, target, version] => (*method, *target, *version),
+        _ => return Ok(Some(Err(Reply::error(400, "malformed request line")))),
+    };
+
+    // HTTP/1.0 closes the connection after every request, unless the client says otherwise
+    let mut close = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Ok(Some(Err(Reply::error(505, "only HTTP/1.x is supported")))),
+    };
+
+    let mut content_length = 0;
+
+    // the headers end with an empty line
+    for count in 0.. {
+        let header = read_line(reader, max_size)?
+            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+
+        if header.is_empty() {
+            break;
+        }
+
+        if count == MAX_HEADERS {
+            return Ok(Some(Err(Reply::error(431, "too many headers"))));
+        }
+
+        let (name, value) = match header.split_once(':') {
+            Some((name, value)) => (name.to_ascii_lowercase(), value.trim()),
+            None => return Ok(Some(Err(Reply::error(400, "malformed header")))),
+        };
+
+        match name.as_str() {
+            "content-length" => match value.parse() {
+                Ok(length) => content_length = length,
+                Err(_) => return Ok(Some(Err(Reply::error(400, "invalid content-length")))),
+            },
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            "transfer-encoding" => {
+                return Ok(Some(Err(Reply::error(
+                    501,
+                    "chunked bodies aren't supported; send a content-length",
+                ))))
+            }
+            // nothing else changes how we handle the request
+            _ => {}
+        }
+    }
+
+    if content_length > max_size {
+        return Ok(Some(Err(Reply::error(
+            413,
+            format!(
+                "body of {} bytes exceeds maximum of {} bytes",
+                content_length, max_size
+            ),
+        ))));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+
+    // nothing we serve takes query parameters
+    let path = target.split('?').next().unwrap_or(target).to_string();
+
+    Ok(Some(Ok(Request {
+        method: method.to_string(),
+        path,
+        body,
+        close,
+    })))
+}
+
+/// work out which endpoint `request` is for, and run it
+fn route(request: &Request, shared: &Shared) -> Reply {
+    // decode each segment on its own, so an escaped slash (%2F) stays part of a counter name
+    let decoded: Option<Vec<String>> = request
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect();
+
+    let decoded = match decoded {
+        Some(decoded) => decoded,
+        None => return Reply::error(400, format!("malformed path: {}", request.path)),
+    };
+
+    let segments: Vec<&str> = decoded.iter().map(String::as_str).collect();
+
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["ping"]) | ("POST", ["ping"]) => ping(shared),
+        ("GET", ["counters"]) => list(shared),
+        ("GET", ["counter"]) => fetch(DEFAULT_COUNTER, shared),
+        ("GET", ["counter", name]) => fetch(name, shared),
+        ("PUT", ["counter"]) => set(DEFAULT_COUNTER, &request.body, shared),
+        ("PUT", ["counter", name]) => set(name, &request.body, shared),
+        ("POST", ["counter", "increment"]) => update(
+            DEFAULT_COUNTER,
+            &request.body,
+            Command::IncrementAndGet,
+            shared,
+        ),
+        ("POST", ["counter", name, "increment"]) => {
+            update(name, &request.body, Command::IncrementAndGet, shared)
+        }
+        ("POST", ["counter", "decrement"]) => update(
+            DEFAULT_COUNTER,
+            &request.body,
+            Command::DecrementAndGet,
+            shared,
+        ),
+        ("POST", ["counter", name, "decrement"]) => {
+            update(name, &request.body, Command::DecrementAndGet, shared)
+        }
+        ("DELETE", ["counter", name]) => delete(name, shared),
+        ("POST", ["command"]) => command(&request.body, shared),
+        (_, ["ping"]) | (_, ["counters"]) | (_, ["counter", ..]) | (_, ["command"]) => {
+            Reply::error(405, format!("{} isn't allowed here", request.method))
+        }
+        _ => Reply::error(404, format!("no such endpoint: {}", request.path)),
+    }
+}
+
+/// decode the `%XX` escapes in a segment of a path, e.g. `a%20b` into `a b`
+///
+/// returns `None` if an escape is malformed or the bytes it decodes to aren't utf-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut bytes = segment.bytes();
+    let mut decoded = Vec::with_capacity(segment.len());
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+
+        let high = (bytes.next()? as char).to_digit(16)?;
+        let low = (bytes.next()? as char).to_digit(16)?;
+        decoded.push((high * 16 + low) as u8);
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+fn ping(shared: &Shared) -> Reply {
+    match execute(&Command::Ping, shared) {
+        Ok(Response::Pong) => Reply::ok(json!({ "reply": "pong" })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+fn list(shared: &Shared) -> Reply {
+    match execute(&Command::List, shared) {
+        Ok(Response::Names(names)) => Reply::ok(json!({ "names": names })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+fn fetch(name: &str, shared: &Shared) -> Reply {
+    match execute(&Command::Fetch(name.to_string()), shared) {
+        Ok(Response::Value(value)) => Reply::ok(json!({ "name": name, "value": value })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+fn set(name: &str, body: &[u8], shared: &Shared) -> Reply {
+    let value = match serde_json::from_slice::<ValueBody>(body) {
+        Ok(body) => body.value,
+        Err(e) => return Reply::error(400, format!("expected {{\"value\": <number>}}: {}", e)),
+    };
+
+    match execute(&Command::Set(name.to_string(), value), shared) {
+        Ok(Response::Ok) => Reply::ok(json!({ "name": name, "value": value })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+/// increment or decrement, replying with the counter's new value
+fn update(name: &str, body: &[u8], command: fn(String, i64) -> Command, shared: &Shared) -> Reply {
+    // the whole body is optional
+    let amount = if body.iter().all(u8::is_ascii_whitespace) {
+        one()
+    } else {
+        match serde_json::from_slice::<AmountBody>(body) {
+            Ok(body) => body.amount,
+            Err(e) => {
+                return Reply::error(400, format!("expected {{\"amount\": <number>}}: {}", e))
+            }
+        }
+    };
+
+    match execute(&command(name.to_string(), amount), shared) {
+        Ok(Response::Value(value)) => Reply::ok(json!({ "name": name, "value": value })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+fn delete(name: &str, shared: &Shared) -> Reply {
+    match execute(&Command::Delete(name.to_string()), shared) {
+        Ok(Response::Ok) => Reply::ok(json!({ "name": name, "deleted": true })),
+        Ok(other) => unexpected(other),
+        Err(reply) => reply,
+    }
+}
+
+/// POST /command: decode and dispatch a `Message` the same way the framed protocol does, and
+/// reply with the `Response` as is
+fn command(body: &[u8], shared: &Shared) -> Reply {
+    let response = match Message::from_slice(body) {
+        Ok(msg) => shared.dispatch(&msg),
+        Err(e) => match error_response(&e) {
+            Some(response) => response,
+            None => return Reply::error(400, e.to_string()),
+        },
+    };
+
+    from_response(response)
+}
+
+/// run `cmd`, turning an error `Response` into the matching error reply
+fn execute(cmd: &Command, shared: &Shared) -> Result<Response, Reply> {
+    match shared.execute(cmd) {
+        Response::Error { code, message } => Err(Reply::from_error(code, message)),
+        response => Ok(response),
+    }
+}
+
+/// reply with the json of `response`, with a status that matches it
+fn from_response(response: Response) -> Reply {
+    let status = match &response {
+        Response::Error { code, .. } => status(*code),
+        _ => 200,
+    };
+
+    Reply {
+        status,
+        body: serde_json::to_value(&response).unwrap_or(Value::Null),
+    }
+}
+
+/// the reply for an error `Response` that comes from the server rather than a command
+fn error_reply(response: Response) -> Reply {
+    match response {
+        Response::Error { code, message } => Reply::from_error(code, message),
+        other => unexpected(other),
+    }
+}
+
+/// the reply for a `Response` that doesn't answer the command that was sent
+fn unexpected(response: Response) -> Reply {
+    Reply::error(500, format!("unexpected reply: {}", response))
+}
+
+/// the HTTP status code that best matches an `ErrorCode`
+fn status(code: ErrorCode) -> u16 {
+    match code {
+        ErrorCode::Malformed | ErrorCode::MissingCommand | ErrorCode::UnsupportedCommand => 400,
+        ErrorCode::Forbidden => 403,
+        ErrorCode::NotFound => 404,
+        ErrorCode::Overflow | ErrorCode::Negative | ErrorCode::BatchAborted => 409,
+        ErrorCode::FrameTooLarge => 413,
+        ErrorCode::Storage => 500,
+        ErrorCode::ServerBusy | ErrorCode::ShuttingDown => 503,
+    }
+}
+
+/// the reason phrase that goes with a status code on the status line
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// turn away an HTTP client with `response`, e.g. a 503 because the server is busy
+pub fn reject(stream: &Stream, response: Response) {
+    let _ = send(stream, &error_reply(response), true);
+}
+
+/// write a single reply to the client
+///
+/// see resp::send
+fn send(mut stream: &Stream, reply: &Reply, close: bool) -> io::Result<()> {
+    let body = reply.body.to_string();
+
+    let mut out = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+        reply.status,
+        reason(reply.status),
+        body.len()
+    );
+
+    if close {
+        out += "Connection: close\r\n";
+    }
+
+    out += "\r\n";
+    out += &body;
+
+    stream.write_all(out.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use client_server::store::Store;
+
+    use super::*;
+    use crate::get_config;
+
+    /// read a single request out of `input`
+    fn read(input: &str) -> io::Result<Option<Result<Request, Reply>>> {
+        read_request(&mut Cursor::new(input.as_bytes()), 64)
+    }
+
+    /// the request in `input`, which must be one we'd serve
+    fn request(input: &str) -> Request {
+        match read(input) {
+            Ok(Some(Ok(request))) => request,
+            _ => panic!("expected a request in {:?}", input),
+        }
+    }
+
+    /// the status of the reply turning away the request in `input`
+    fn refusal(input: &str) -> u16 {
+        match read(input) {
+            Ok(Some(Err(reply))) => reply.status,
+            _ => panic!("expected {:?} to be refused", input),
+        }
+    }
+
+    /// a server started with `args` on its command line, with no counters yet
+    fn shared(args: &[&str]) -> Shared {
+        let config = get_config(["server"].iter().chain(args));
+        let store = Store::new(config.overflow);
+
+        Shared::new(config, store)
+    }
+
+    /// route a request for `method` and `path` with `body`, returning the status and body
+    fn call(shared: &Shared, method: &str, path: &str, body: &str) -> (u16, Value) {
+        let request = Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            body: body.as_bytes().to_vec(),
+            close: false,
+        };
+
+        let reply = route(&request, shared);
+        (reply.status, reply.body)
+    }
+
+    #[test]
+    fn reads_a_request() {
+        let request = request(
+            "POST /counter/increment?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 13\r\n\r\n\
+             {\"amount\": 5}",
+        );
+
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/counter/increment");
+        assert_eq!(request.body, b"{\"amount\": 5}");
+        assert!(!request.close);
+    }
+
+    #[test]
+    fn reads_whether_to_close_the_connection() {
+        assert!(request("GET / HTTP/1.0\r\n\r\n").close);
+        assert!(!request("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").close);
+        assert!(request("GET / HTTP/1.1\r\nconnection: close\r\n\r\n").close);
+    }
+
+    #[test]
+    fn end_of_stream_before_a_request_is_none() {
+        assert!(matches!(read(""), Ok(None)));
+    }
+
+    #[test]
+    fn end_of_stream_within_a_request_is_an_error() {
+        for input in &[
+            "GET / HTTP/1.1\r\n",
+            "GET / HTTP/1.1\r\nHost: a\r\n",
+            "PUT / HTTP/1.1\r\nContent-Length: 5\r\n\r\n{}",
+        ] {
+            let e = read(input).err().expect(input);
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn refuses_bad_requests() {
+        assert_eq!(refusal("GET /\r\n\r\n"), 400);
+        assert_eq!(refusal("GET / HTTP/2\r\n\r\n"), 505);
+        assert_eq!(refusal("GET / HTTP/1.1\r\nno colon\r\n\r\n"), 400);
+        assert_eq!(refusal("GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), 400);
+        assert_eq!(
+            refusal("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            501
+        );
+        assert_eq!(refusal("PUT / HTTP/1.1\r\nContent-Length: 65\r\n\r\n"), 413);
+        assert_eq!(
+            refusal(&format!("GET / HTTP/1.1\r\n{}\r\n", "A: b\r\n".repeat(65))),
+            431
+        );
+    }
+
+    #[test]
+    fn lines_over_the_limit_are_an_error() {
+        let input = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(100));
+        let e = read(&input).err().expect("an error");
+
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn routes_counter_endpoints() {
+        let shared = shared(&[]);
+
+        assert_eq!(
+            call(&shared, "GET", "/ping", ""),
+            (200, json!({ "reply": "pong" }))
+        );
+        assert_eq!(
+            call(&shared, "PUT", "/counter/visits", r#"{"value": 5}"#),
+            (200, json!({ "name": "visits", "value": 5 }))
+        );
+        assert_eq!(
+            call(&shared, "POST", "/counter/visits/increment", ""),
+            (200, json!({ "name": "visits", "value": 6 }))
+        );
+        assert_eq!(
+            call(
+                &shared,
+                "POST",
+                "/counter/visits/decrement",
+                r#"{"amount": 4}"#
+            ),
+            (200, json!({ "name": "visits", "value": 2 }))
+        );
+        assert_eq!(
+            call(&shared, "POST", "/counter/increment", r#"{"amount": 3}"#),
+            (200, json!({ "name": DEFAULT_COUNTER, "value": 3 }))
+        );
+        assert_eq!(
+            call(&shared, "GET", "/counters", ""),
+            (200, json!({ "names": [DEFAULT_COUNTER, "visits"] }))
+        );
+        assert_eq!(
+            call(&shared, "DELETE", "/counter/visits", ""),
+            (200, json!({ "name": "visits", "deleted": true }))
+        );
+        assert_eq!(call(&shared, "GET", "/counter/visits", "").0, 404);
+    }
+
+    #[test]
+    fn decodes_counter_names() {
+        let shared = shared(&[]);
+
+        assert_eq!(
+            call(&shared, "PUT", "/counter/a%20b", r#"{"value": 1}"#),
+            (200, json!({ "name": "a b", "value": 1 }))
+        );
+        assert_eq!(
+            call(&shared, "PUT", "/counter/a%2Fb%c3%a9", r#"{"value": 2}"#).1["name"],
+            "a/bé"
+        );
+        assert_eq!(
+            call(&shared, "GET", "/counters", "").1["names"],
+            json!(["a b", "a/bé"])
+        );
+
+        for path in &["/counter/a%2", "/counter/a%zz", "/counter/%ff"] {
+            assert_eq!(call(&shared, "GET", path, "").0, 400, "{}", path);
+        }
+    }
+
+    #[test]
+    fn routes_commands() {
+        let shared = shared(&[]);
+
+        let (status, body) = call(
+            &shared,
+            "POST",
+            "/command",
+            r#"{"cmd": {"IncrementAndGet": ["x", 2]}}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body, json!({ "Value": 2 }));
+
+        assert_eq!(call(&shared, "POST", "/command", "not json").0, 400);
+    }
+
+    #[test]
+    fn refuses_unknown_endpoints_and_methods() {
+        let shared = shared(&[]);
+
+        assert_eq!(call(&shared, "GET", "/nope", "").0, 404);
+        assert_eq!(call(&shared, "DELETE", "/counters", "").0, 405);
+        assert_eq!(call(&shared, "GET", "/command", "").0, 405);
+        assert_eq!(call(&shared, "PUT", "/counter", "{}").0, 400);
+        assert_eq!(
+            call(
+                &shared,
+                "POST",
+                "/counter/x/increment",
+                r#"{"amount": "5"}"#
+            )
+            .0,
+            400
+        );
+    }
+
+    #[test]
+    fn errors_from_commands_get_a_matching_status() {
+        let shared = shared(&["--overflow", "reject"]);
+
+        call(
+            &shared,
+            "PUT",
+            "/counter/x",
+            &format!(r#"{{"value": {}}}"#, i64::MAX),
+        );
+        let (code, body) = call(&shared, "POST", "/counter/x/increment", "");
+
+        assert_eq!(code, 409);
+        assert_eq!(body["error"]["code"], json!(ErrorCode::Overflow));
+    }
+
+    #[test]
+    fn maps_errors_to_statuses() {
+        assert_eq!(status(ErrorCode::Malformed), 400);
+        assert_eq!(status(ErrorCode::Forbidden), 403);
+        assert_eq!(status(ErrorCode::NotFound), 404);
+        assert_eq!(status(ErrorCode::FrameTooLarge), 413);
+        assert_eq!(status(ErrorCode::Storage), 500);
+        assert_eq!(status(ErrorCode::ServerBusy), 503);
+        assert_eq!(status(ErrorCode::ShuttingDown), 503);
+    }
+}
@@ -0,0 +1,756 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use clap::{App, Arg}; // command line parsing
+
+use client_server::counter::OverflowPolicy;
+use client_server::persistence::FsyncPolicy;
+use client_server::protocol::{
+    Command, ErrorCode, Message, ProtocolError, Response, DEFAULT_MAX_FRAME_SIZE,
+};
+use client_server::store::Store;
+
+mod asynchronous;
+mod http;
+mod memcached;
+mod pool;
+mod resp;
+mod shutdown;
+mod text;
+mod threaded;
+mod timeouts;
+mod transport;
+mod udp;
+
+use pool::{Handler, OnFull, Rejecter};
+use shutdown::Shutdown;
+use timeouts::Timeouts;
+use transport::Listener;
+
+/// Which implementation accepts and serves connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Runtime {
+    /// one OS thread per connection, blocking i/o
+    Threaded,
+
+    /// tokio tasks on a small pool of threads, non-blocking i/o
+    Async,
+}
+
+/// Wire protocols the server speaks, each on a port of its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// length-prefixed json `Message`s, see `client_server::protocol`
+    Framed,
+
+    /// the Redis serialization protocol, so redis-cli and redis-benchmark can talk to us
+    Resp,
+
+    /// one command per line, e.g. `INCR 5`, for people and shell scripts
+    Text,
+
+    /// the memcached text protocol's get, set, incr and decr, for existing memcached clients
+    Memcached,
+
+    /// json over HTTP, for dashboards, scripts and anything else that already speaks HTTP
+    Http,
+}
+
+impl Protocol {
+    /// short name used in log messages
+    pub fn name(self) -> &'static str {
+        match self {
+            Protocol::Framed => "framed json",
+            Protocol::Resp => "resp",
+            Protocol::Text => "text",
+            Protocol::Memcached => "memcached",
+            Protocol::Http => "http",
+        }
+    }
+
+    /// the function a worker thread calls to serve a connection that speaks this protocol
+    pub fn handler(self) -> Handler {
+        match self {
+            Protocol::Framed => threaded::handle_connection,
+            Protocol::Resp => resp::handle_connection,
+            Protocol::Text => text::handle_connection,
+            Protocol::Memcached => memcached::handle_connection,
+            Protocol::Http => http::handle_connection,
+        }
+    }
+
+    /// the function that turns away a client speaking this protocol, e.g. when the server is
+    /// busy; a client should get an error it can make sense of, not a reply in some other
+    /// protocol
+    pub fn rejecter(self) -> Rejecter {
+        match self {
+            Protocol::Framed => threaded::reject,
+            Protocol::Resp => resp::reject,
+            Protocol::Text => text::reject,
+            Protocol::Memcached => memcached::reject,
+            Protocol::Http => http::reject,
+        }
+    }
+}
+
+/// runtime configuration for the server, built from command line arguments
+pub struct Config {
+    /// addresses to accept connections on, each protocol on a port of its own
+    pub listen: Vec<IpAddr>,
+
+    /// port to accept framed protocol connections on, on every listen address; `None` when the
+    /// server only takes framed clients on a unix socket
+    pub port: Option<u16>,
+
+    /// path of a unix domain socket to accept framed protocol connections on, as well as (or
+    /// instead of) `listen`
+    pub unix_socket: Option<PathBuf>,
+
+    /// file permissions given to `unix_socket`, e.g. 0o660 for its owner and group only
+    pub unix_socket_mode: u32,
+
+    /// port to accept redis (RESP) connections on, on every listen address; `None` turns the
+    /// RESP listener off
+    pub resp_port: Option<u16>,
+
+    /// port to accept text protocol connections on, on every listen address. The text protocol
+    /// is also recognized on the main port, so this is only needed by clients that can't send
+    /// first
+    pub text_port: Option<u16>,
+
+    /// port to accept memcached connections on, on every listen address; `None` turns the
+    /// memcached listener off
+    pub memcached_port: Option<u16>,
+
+    /// port to accept HTTP requests on, on every listen address; `None` turns the HTTP listener
+    /// off
+    pub http_port: Option<u16>,
+
+    /// port to accept fire-and-forget udp increments and decrements on, on every listen
+    /// address; `None` turns the udp listener off
+    pub udp_port: Option<u16>,
+
+    /// how connections are served
+    pub runtime: Runtime,
+
+    /// number of worker threads serving connections (threaded runtime only)
+    pub workers: usize,
+
+    /// number of accepted connections that may wait for a free worker (threaded runtime only)
+    pub queue_size: usize,
+
+    /// what to do with connections that arrive when the queue is full (threaded runtime only)
+    pub on_full: OnFull,
+
+    /// largest frame (in bytes) the server will accept from a client
+    pub max_frame_size: usize,
+
+    /// how long the server waits on slow or silent clients
+    pub timeouts: Timeouts,
+
+    /// how the counter behaves when an update would overflow it
+    pub overflow: OverflowPolicy,
+
+    /// where to keep the write-ahead log and snapshots; `None` keeps everything in memory
+    pub data_dir: Option<PathBuf>,
+
+    /// when to fsync the write-ahead log
+    pub fsync: FsyncPolicy,
+
+    /// number of logged commands between snapshots
+    pub snapshot_every: u64,
+
+    /// how long to wait for in-flight requests to finish once a shutdown begins
+    pub shutdown_timeout: Duration,
+
+    /// whether clients may shut the server down with `Command::Shutdown`
+    pub allow_remote_shutdown: bool,
+}
+
+/// parse command line arguments, e.g. `env::args_os()`, and return them as a `Config`
+fn get_config<I, T>(args: I) -> Config
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    // clap wants the default as a &str, so we need an owned String that outlives `app`
+    let default_max = DEFAULT_MAX_FRAME_SIZE.to_string();
+
+    let app = App::new("server")
+        .arg(
+            Arg::with_name("listen")
+                .long("listen")
+                .short("l")
+                .help("Address(es) to listen on; use -l 0.0.0.0 -l :: for both IPv4 and IPv6")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .default_value("0.0.0.0"),
+        )
+        .arg(
+            Arg::with_name("port")
+                .long("port")
+                .short("p")
+                .help("Port to listen on")
+                .takes_value(true)
+                .default_value("4444"),
+        )
+        .arg(
+            Arg::with_name("unix_socket")
+                .long("unix-socket")
+                .help("Also accept clients on this unix socket (without --listen, --port is off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unix_socket_mode")
+                .long("unix-socket-mode")
+                .help("Permissions for --unix-socket, in octal; connecting takes write access")
+                .takes_value(true)
+                .default_value("660"),
+        )
+        .arg(
+            Arg::with_name("resp_port")
+                .long("resp-port")
+                .help("Also accept redis clients (RESP protocol) on this port (default: off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("text_port")
+                .long("text-port")
+                .help("Also accept text protocol clients on this port (default: off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("memcached_port")
+                .long("memcached-port")
+                .help("Also accept memcached clients on this port (default: off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http_port")
+                .long("http-port")
+                .help("Also serve a json HTTP API on this port (default: off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("udp_port")
+                .long("udp-port")
+                .help("Also apply increments and decrements sent as udp datagrams (default: off)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("runtime")
+                .long("runtime")
+                .help("Serve connections with a thread per connection, or with async tokio tasks")
+                .takes_value(true)
+                .possible_values(&["threaded", "async"])
+                .default_value("threaded"),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .help("Number of worker threads, i.e. connections served at once (threaded only)")
+                .takes_value(true)
+                .default_value("128"),
+        )
+        .arg(
+            Arg::with_name("queue_size")
+                .long("queue-size")
+                .help("Number of connections that may wait for a free worker (threaded only)")
+                .takes_value(true)
+                .default_value("1024"),
+        )
+        .arg(
+            Arg::with_name("on_full")
+                .long("on-full")
+                .help("Reject new connections or make them wait when the queue is full")
+                .takes_value(true)
+                .possible_values(&["reject", "wait"])
+                .default_value("wait"),
+        )
+        .arg(
+            Arg::with_name("max_frame_size")
+                .long("max-frame-size")
+                .help("Largest message (in bytes) the server will accept from a client")
+                .takes_value(true)
+                .default_value(&default_max),
+        )
+        .arg(
+            Arg::with_name("idle_timeout")
+                .long("idle-timeout")
+                .help("Seconds a connection may sit idle between messages (0 = forever)")
+                .takes_value(true)
+                .default_value("60"),
+        )
+        .arg(
+            Arg::with_name("read_timeout")
+                .long("read-timeout")
+                .help("Seconds to wait for the rest of a message once it has started (0 = forever)")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("write_timeout")
+                .long("write-timeout")
+                .help("Seconds a client may take to accept a reply (0 = forever)")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("min_receive_rate")
+                .long("min-receive-rate")
+                .help("Close connections sending a message slower than this many bytes/s (0 = off)")
+                .takes_value(true)
+                .default_value("128"),
+        )
+        .arg(
+            Arg::with_name("overflow")
+                .long("overflow")
+                .help("What to do when an update would overflow the counter")
+                .takes_value(true)
+                .possible_values(&["wrap", "saturate", "reject"])
+                .default_value("wrap"),
+        )
+        .arg(
+            Arg::with_name("data_dir")
+                .long("data-dir")
+                .help("Directory used to persist counters across restarts (default: memory only)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fsync")
+                .long("fsync")
+                .help("When to flush the write-ahead log to disk")
+                .takes_value(true)
+                .possible_values(&["always", "everysec", "never"])
+                .default_value("everysec"),
+        )
+        .arg(
+            Arg::with_name("snapshot_every")
+                .long("snapshot-every")
+                .help("Number of logged commands between snapshots (0 disables snapshots)")
+                .takes_value(true)
+                .default_value("10000"),
+        )
+        .arg(
+            Arg::with_name("shutdown_timeout")
+                .long("shutdown-timeout")
+                .help("Seconds to wait for in-flight requests to finish when shutting down")
+                .takes_value(true)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("allow_remote_shutdown")
+                .long("allow-remote-shutdown")
+                .help("Let clients shut the server down by sending a Shutdown command"),
+        );
+
+    let matches = app.get_matches_from(args);
+
+    // we provide a default to each Arg; these will always have a value/can't fail
+    let port: u16 = matches
+        .value_of("port")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --port value to u16");
+
+    let unix_socket = matches.value_of("unix_socket").map(PathBuf::from);
+
+    // permissions are traditionally written in octal (rwx = 3 bits = 1 octal digit), so 660 is
+    // read and write for the owner and group, and nothing for anyone else
+    let unix_socket_mode = u32::from_str_radix(matches.value_of("unix_socket_mode").unwrap(), 8)
+        .expect("Couldn't parse --unix-socket-mode value as an octal number");
+
+    // IpAddr parses both IPv4 (127.0.0.1) and IPv6 (::1) addresses
+    let listen = matches
+        .values_of("listen")
+        .unwrap()
+        .map(|addr| {
+            addr.parse()
+                .expect("Couldn't parse --listen value as an IP address")
+        })
+        .collect();
+
+    // clients on the same machine can use the socket, so unless tcp was asked for explicitly,
+    // don't expose the framed port at all. The optional ports are still bound on the default
+    // address, since they were asked for by name and a unix socket only speaks framed json
+    let port = if unix_socket.is_some() && matches.occurrences_of("listen") == 0 {
+        None
+    } else {
+        Some(port)
+    };
+
+    // the optional listeners have no default; no value means they're turned off
+    let resp_port = matches.value_of("resp_port").map(|port| {
+        port.parse()
+            .expect("Couldn't cast --resp-port value to u16")
+    });
+    let text_port = matches.value_of("text_port").map(|port| {
+        port.parse()
+            .expect("Couldn't cast --text-port value to u16")
+    });
+    let memcached_port = matches.value_of("memcached_port").map(|port| {
+        port.parse()
+            .expect("Couldn't cast --memcached-port value to u16")
+    });
+    let http_port = matches.value_of("http_port").map(|port| {
+        port.parse()
+            .expect("Couldn't cast --http-port value to u16")
+    });
+    let udp_port = matches
+        .value_of("udp_port")
+        .map(|port| port.parse().expect("Couldn't cast --udp-port value to u16"));
+
+    let max_frame_size = matches
+        .value_of("max_frame_size")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --max-frame-size value to usize");
+
+    // every timeout is given in whole seconds, with 0 turning it off
+    let seconds = |name: &str| {
+        let secs =
+            matches.value_of(name).unwrap().parse().unwrap_or_else(|_| {
+                panic!("Couldn't cast --{} value to u64", name.replace('_', "-"))
+            });
+
+        Timeouts::from_secs(secs)
+    };
+
+    let min_receive_rate = matches
+        .value_of("min_receive_rate")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --min-receive-rate value to u64");
+
+    let timeouts = Timeouts {
+        idle: seconds("idle_timeout"),
+        read: seconds("read_timeout"),
+        write: seconds("write_timeout"),
+        min_receive_rate: if min_receive_rate == 0 {
+            None
+        } else {
+            Some(min_receive_rate)
+        },
+    };
+
+    // possible_values already limited the input to one of these
+    let runtime = match matches.value_of("runtime").unwrap() {
+        "async" => Runtime::Async,
+        _ => Runtime::Threaded,
+    };
+
+    let workers = matches
+        .value_of("workers")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --workers value to usize");
+
+    // with no workers, every connection would sit in the queue forever
+    assert!(workers > 0, "--workers must be at least 1");
+
+    let queue_size = matches
+        .value_of("queue_size")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --queue-size value to usize");
+
+    // possible_values already limited the input to something from_str understands
+    let on_full = matches.value_of("on_full").unwrap().parse().unwrap();
+    let overflow = matches.value_of("overflow").unwrap().parse().unwrap();
+    let fsync = matches.value_of("fsync").unwrap().parse().unwrap();
+
+    let snapshot_every = matches
+        .value_of("snapshot_every")
+        .unwrap()
+        .parse()
+        .expect("Couldn't cast --snapshot-every value to u64");
+
+    let shutdown_timeout = matches
+        .value_of("shutdown_timeout")
+        .unwrap()
+        .parse()
+        .map(Duration::from_secs)
+        .expect("Couldn't cast --shutdown-timeout value to u64");
+
+    // flags without a value are either there or they aren't
+    let allow_remote_shutdown = matches.is_present("allow_remote_shutdown");
+
+    // data_dir has no default, so its absence means persistence is turned off
+    let data_dir = matches.value_of("data_dir").map(PathBuf::from);
+
+    Config {
+        listen,
+        port,
+        unix_socket,
+        unix_socket_mode,
+        resp_port,
+        text_port,
+        memcached_port,
+        http_port,
+        udp_port,
+        runtime,
+        workers,
+        queue_size,
+        on_full,
+        max_frame_size,
+        timeouts,
+        overflow,
+        data_dir,
+        fsync,
+        snapshot_every,
+        shutdown_timeout,
+        allow_remote_shutdown,
+    }
+}
+
+/// State shared by every connection handler, regardless of which runtime is serving it
+pub struct Shared {
+    /// settings parsed from the command line
+    pub config: Config,
+
+    /// the server's named counters
+    pub store: Store,
+
+    /// tracks whether the server is shutting down, and which requests are still running
+    pub shutdown: Shutdown,
+
+    /// id handed to the next accepted connection, shared by every listener so ids are unique
+    next_id: AtomicUsize,
+}
+
+impl Shared {
+    /// the state of a server that hasn't accepted a connection yet
+    pub fn new(config: Config, store: Store) -> Self {
+        Self {
+            config,
+            store,
+            shutdown: Shutdown::default(),
+            next_id: AtomicUsize::new(0),
+        }
+    }
+
+    /// get a unique id for a newly accepted connection, used to tag its log messages
+    pub fn next_connection_id(&self) -> usize {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// build the Response to a single, successfully decoded Message
+    pub fn dispatch(&self, msg: &Message) -> Response {
+        match &msg.cmd {
+            Some(cmd) => self.execute(cmd),
+            None => {
+                // a message without a command isn't a no-op, it's a client bug; say so instead of
+                // pretending something succeeded
+                Response::error(ErrorCode::MissingCommand, "message has no command")
+            }
+        }
+    }
+
+    /// execute a single Command, whichever protocol it arrived over, and build its Response
+    pub fn execute(&self, cmd: &Command) -> Response {
+        match cmd {
+            Command::Shutdown => self.remote_shutdown(),
+            cmd => self.store.execute(cmd),
+        }
+    }
+
+    /// handle a `Command::Shutdown` sent by a client
+    fn remote_shutdown(&self) -> Response {
+        // anyone who can connect can send this, so it's off unless explicitly turned on
+        if !self.config.allow_remote_shutdown {
+            return Response::error(
+                ErrorCode::Forbidden,
+                "remote shutdown is disabled; start the server with --allow-remote-shutdown",
+            );
+        }
+
+        if self.shutdown.request() {
+            println!("shutdown requested by a client");
+        }
+
+        // the request asking for the shutdown is itself in flight, so the client still gets
+        // this reply before the server goes away
+        Response::Ok
+    }
+}
+
+/// Build the error Response for a message that couldn't be read
+///
+/// returns `None` when the connection itself is broken or gone and there's nobody left to reply
+/// to
+pub fn error_response(e: &ProtocolError) -> Option<Response> {
+    let code = match e {
+        ProtocolError::Oversize { .. } => ErrorCode::FrameTooLarge,
+        ProtocolError::UnsupportedCommand(_) => ErrorCode::UnsupportedCommand,
+        ProtocolError::Decode(_) => ErrorCode::Malformed,
+        ProtocolError::Io(_) | ProtocolError::Encode(_) | ProtocolError::Eof => return None,
+    };
+
+    Some(Response::error(code, e.to_string()))
+}
+
+/// bind a listener for `protocol` to `addr`, exiting if that's not possible
+fn bind(addr: SocketAddr, protocol: Protocol) -> (Listener, Protocol) {
+    let listener =
+        Listener::bind_tcp(addr).unwrap_or_else(|e| panic!("Couldn't bind {}: {}", addr, e));
+
+    println!("listening on {} ({})", addr, protocol.name());
+    (listener, protocol)
+}
+
+fn main() {
+    // parse --max-frame-size and friends from the command line
+    let config = get_config(env::args_os());
+
+    // bind every address up front, so a typo or a port that's already taken stops the server
+    // right away instead of leaving it half started
+    let mut listeners: Vec<(Listener, Protocol)> = Vec::new();
+
+    // every protocol listens on the same addresses, each on a port of its own; a port of None
+    // means that protocol is turned off
+    let ports = [
+        (config.port, Protocol::Framed),
+        (config.resp_port, Protocol::Resp),
+        (config.text_port, Protocol::Text),
+        (config.memcached_port, Protocol::Memcached),
+        (config.http_port, Protocol::Http),
+    ];
+
+    for (port, protocol) in ports.iter() {
+        if let Some(port) = port {
+            for ip in &config.listen {
+                listeners.push(bind(SocketAddr::new(*ip, *port), *protocol));
+            }
+        }
+    }
+
+    if let Some(path) = &config.unix_socket {
+        let listener = Listener::bind_unix(path, config.unix_socket_mode)
+            .unwrap_or_else(|e| panic!("Couldn't bind {}: {}", path.display(), e));
+
+        println!(
+            "listening on {} ({})",
+            path.display(),
+            Protocol::Framed.name()
+        );
+        listeners.push((listener, Protocol::Framed));
+    }
+
+    // udp has no connections to accept, so its sockets aren't listeners; each one gets a thread
+    // of its own once the server is up
+    let mut udp_sockets = Vec::new();
+
+    if let Some(port) = config.udp_port {
+        for ip in &config.listen {
+            let addr = SocketAddr::new(*ip, port);
+            let socket = transport::bind_udp(addr)
+                .unwrap_or_else(|e| panic!("Couldn't bind {}: {}", addr, e));
+
+            println!("listening on {} (udp)", addr);
+            udp_sockets.push(socket);
+        }
+    }
+
+    // `store` holds the server's named counters.
+    //
+    // Store is a map of names to counters, each of which wraps an AtomicI64, an integer type
+    // which can be safely shared between threads
+    let store = match &config.data_dir {
+        Some(dir) => Store::open(config.overflow, dir, config.fsync, config.snapshot_every)
+            .expect("Couldn't restore state from data directory"),
+        None => Store::new(config.overflow),
+    };
+
+    // `shared` bundles the store with the config, so every connection handler can get at both.
+    //
+    // An Arc is a thread-safe reference-counting pointer.
+    // 'Arc' stands for 'Atomically Reference Counted'. Arc uses atomic operations for its
+    // reference counting and is thread-safe. It allows us to share immutable data across threads.
+    // The reason for needing the Arc type when attempting to share data across threads is to
+    // ensure that the lifetime of the type that is being shared, lives as long as the longest
+    // lasting thread.
+    //
+    // the use of these types together means we'll have a server that manipulates shared data
+    // from many threads (or tasks), but is free of data races.
+    let shared = Arc::new(Shared::new(config, store));
+
+    // the handler runs on its own thread whenever the process receives SIGINT (ctrl+c) or
+    // SIGTERM. The first signal starts a graceful shutdown; if that's taking too long, a second
+    // one gets you out immediately
+    let handler_ref = shared.clone();
+
+    ctrlc::set_handler(move || {
+        if handler_ref.shutdown.request() {
+            println!("shutting down; signal again to exit immediately");
+        } else {
+            eprintln!("exiting without waiting for in-flight requests");
+            process::exit(1);
+        }
+    })
+    .expect("Couldn't install signal handler");
+
+    let udp_threads: Vec<_> = udp_sockets
+        .into_iter()
+        .map(|socket| {
+            let shared = shared.clone();
+            thread::spawn(move || udp::serve(socket, shared))
+        })
+        .collect();
+
+    // serve returns once a shutdown has been requested, no new connections are being accepted
+    // and in-flight requests have finished (or the shutdown timeout ran out)
+    match shared.config.runtime {
+        Runtime::Threaded => threaded::serve(listeners, shared.clone()),
+        Runtime::Async => {
+            // only the framed protocol has an async implementation. The others are served by the
+            // worker pool, on threads of their own, alongside the tokio runtime
+            let (framed, others): (Vec<_>, Vec<_>) = listeners
+                .into_iter()
+                .partition(|(_, protocol)| *protocol == Protocol::Framed);
+
+            let pool_ref = shared.clone();
+            let others = if others.is_empty() {
+                None
+            } else {
+                Some(thread::spawn(move || threaded::serve(others, pool_ref)))
+            };
+
+            let framed = framed.into_iter().map(|(listener, _)| listener).collect();
+            asynchronous::serve(framed, shared.clone());
+
+            if let Some(others) = others {
+                let _ = others.join();
+            }
+        }
+    }
+
+    // udp threads stop reading within POLL_INTERVAL of the shutdown, and the datagram each was
+    // applying has already been waited for as an in-flight request
+    for handle in udp_threads {
+        let _ = handle.join();
+    }
+
+    // unlike a port, a unix socket's file sticks around after we're gone; clean up after ourselves
+    if let Some(path) = &shared.config.unix_socket {
+        if let Err(e) = fs::remove_file(path) {
+            eprintln!("couldn't remove {}: {}", path.display(), e);
+        }
+    }
+
+    // whatever the write-ahead log hasn't synced yet would otherwise be at the mercy of the OS
+    if let Err(e) = shared.store.flush() {
+        eprintln!("couldn't flush state to disk: {}", e);
+        process::exit(1);
+    }
+
+    println!("shutdown complete");
+}
@@ -0,0 +1,509 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+use client_server::protocol::{Command, ErrorCode, Response};
+
+use crate::resp::read_line;
+use crate::shutdown::shutting_down;
+use crate::timeouts::TimedReader;
+use crate::transport::Stream;
+use crate::Shared;
+
+/// What a single request from a memcached client asks for
+enum Request {
+    /// `get <key>*`: the values of any number of counters
+    Get(Vec<String>),
+
+    /// `set <key> <flags> <exptime> <bytes>`, followed by the value on a line of its own
+    Set(String, i64),
+
+    /// `incr <key> <delta>`
+    Incr(String, u64),
+
+    /// `decr <key> <delta>`
+    Decr(String, u64),
+
+    /// `delete <key>`
+    Delete(String),
+
+    /// `version`
+    Version,
+
+    /// `quit`: hang up without replying
+    Quit,
+}
+
+/// Serve a connection from a memcached client
+///
+/// Only the commands that make sense for counters are understood: `get`, `set`, `incr`, `decr`
+/// and `delete`, plus `version` and `quit`. Each is translated into the server's own `Command`s
+/// and run against the same counters as every other protocol, following memcached's rules where
+/// they differ from ours:
+///
+/// - `incr` and `decr` on a missing key reply `NOT_FOUND` instead of creating the counter
+/// - `decr` stops at 0 rather than going negative
+/// - `incr` and `decr` refuse a counter holding a negative value (which another protocol may
+///   have left behind), just like memcached refuses one that doesn't hold a number
+/// - `set` only accepts values that are integers, since that's all a counter can hold. Flags
+///   are ignored (`get` always reports 0) and so is the expiry time; counters never expire
+///
+/// One difference remains: memcached's counters are unsigned 64-bit numbers and `incr` wraps
+/// around at 2^64, but ours are signed and top out at 2^63 - 1. Rather than wrap somewhere
+/// memcached wouldn't, an `incr` that would go past that is refused with a `CLIENT_ERROR`. Any
+/// delta memcached accepts is accepted here too; a `decr` by more than the counter holds
+/// simply stops at 0
+pub fn handle_connection(id: usize, stream: Stream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+
+    if let Err(e) = stream.set_write_timeout(timeouts.write) {
+        eprintln!("[{:7}] couldn't set write timeout: {}", id, e);
+        return;
+    }
+
+    // see resp::handle_connection
+    let mut reader = BufReader::new(TimedReader::new(&stream, timeouts));
+
+    loop {
+        reader.get_mut().next_message();
+
+        let (request, noreply) = match read_request(&mut reader, shared.config.max_frame_size) {
+            Ok(Some(Ok(request))) => request,
+            // a malformed command line is answered, and the connection carries on
+            Ok(Some(Err(error))) => {
+                if let Err(e) = send(&stream, &error) {
+                    eprintln!("[{:7}] couldn't send reply: {}", id, e);
+                    return;
+                }
+
+                continue;
+            }
+            // the client hung up between requests
+            Ok(None) => return,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                // a broken data block leaves us with no idea where the next command starts, so
+                // memcached hangs up; so do we
+                println!("[{:7}] {}; closing connection", id, e);
+                let _ = send(&stream, &format!("CLIENT_ERROR {}", e));
+                return;
+            }
+            Err(e) => {
+                eprintln!("[{:7}] {}", id, e);
+                return;
+            }
+        };
+
+        let reply = match request {
+            Request::Quit => return,
+            request => {
+                // see threaded::handle_connection
+                let _request = match shared.shutdown.begin_request() {
+                    Some(request) => request,
+                    None => {
+                        let _ = send(&stream, &server_error(shutting_down()));
+                        return;
+                    }
+                };
+
+                execute(id, request, |cmd| shared.execute(cmd))
+            }
+        };
+
+        if !noreply {
+            if let Err(e) = send(&stream, &reply) {
+                eprintln!("[{:7}] couldn't send reply: {}", id, e);
+                return;
+            }
+        }
+
+        if shared.shutdown.is_requested() {
+            return;
+        }
+    }
+}
+
+/// Read a single request, along with whether the client asked not to be sent a reply
+///
+/// returns `None` when the client closed the connection before starting a new request, and
+/// `Some(Err(reply))` for a command line that doesn't make sense. An `InvalidData` error means
+/// the data block following a `set` was malformed
+fn read_request<R: BufRead>(
+    reader: &mut R,
+    max_size: usize,
+) -> io::Result<Option<Result<(Request, bool), String>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut words: Vec<&str> = line.split_whitespace().collect();
+
+    // storage, incr, decr and delete commands can all end with "noreply", for clients that
+    // would rather not wait for an answer. get can't, so there it's just a key
+    let noreply =
+        words.len() > 2 && words.last() == Some(&"noreply") && !matches!(words[0], "get" | "gets");
+
+    if noreply {
+        words.pop();
+    }
+
+    let request = match words.as_slice() {
+        ["get", keys @ ..] | ["gets", keys @ ..] if !keys.is_empty() => {
+            Request::Get(keys.iter().map(|key| key.to_string()).collect())
+        }
+        ["set", key, _flags, _exptime, bytes] => {
+            let bytes = match bytes.parse::<usize>() {
+                Ok(bytes) => bytes,
+                Err(_) => return Ok(Some(Err(bad_format()))),
+            };
+
+            // the data block has to be read even if we're going to refuse it, or it would be
+            // taken for the next command
+            let data = read_data(reader, bytes, max_size)?;
+
+            match data.trim().parse() {
+                Ok(value) => Request::Set(key.to_string(), value),
+                Err(_) => {
+                    return Ok(Some(Err(
+                        "CLIENT_ERROR counters can only hold integers".to_string()
+                    )))
+                }
+            }
+        }
+        ["incr", key, delta] | ["decr", key, delta] => {
+            let delta = match delta.parse::<u64>() {
+                Ok(delta) => delta,
+                Err(_) => {
+                    return Ok(Some(Err(
+                        "CLIENT_ERROR invalid numeric delta argument".to_string()
+                    )))
+                }
+            };
+
+            if words[0] == "incr" {
+                Request::Incr(key.to_string(), delta)
+            } else {
+                Request::Decr(key.to_string(), delta)
+            }
+        }
+        ["delete", key] => Request::Delete(key.to_string()),
+        ["version"] => Request::Version,
+        ["quit"] => Request::Quit,
+        ["get"] | ["gets"] | ["set", ..] | ["incr", ..] | ["decr", ..] | ["delete", ..] => {
+            return Ok(Some(Err(bad_format())))
+        }
+        // memcached's reply to a command it doesn't know, or an empty line
+        _ => return Ok(Some(Err("ERROR".to_string()))),
+    };
+
+    Ok(Some(Ok((request, noreply))))
+}
+
+/// read the data block of a `set`: exactly `bytes` bytes, followed by `\r\n`
+fn read_data<R: Read>(reader: &mut R, bytes: usize, max_size: usize) -> io::Result<String> {
+    if bytes > max_size {
+        return Err(invalid(format!(
+            "value of {} bytes exceeds maximum of {} bytes",
+            bytes, max_size
+        )));
+    }
+
+    let mut buf = vec![0; bytes + 2];
+    reader.read_exact(&mut buf)?;
+
+    if !buf.ends_with(b"\r\n") {
+        return Err(invalid("bad data chunk"));
+    }
+
+    buf.truncate(bytes);
+    String::from_utf8(buf).map_err(|_| invalid("bad data chunk"))
+}
+
+/// run a request against the counters and build its reply
+///
+/// `run` executes a single `Command`; that's `Shared::execute`, except in the tests below
+fn execute(id: usize, request: Request, run: impl Fn(&Command) -> Response) -> String {
+    match request {
+        Request::Get(keys) => {
+            let cmd = Command::FetchMany(keys.clone());
+            let response = run(&cmd);
+            println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+            match response {
+                // each counter that exists gets a VALUE line (with flags and length) followed by
+                // the value itself; missing ones are simply left out
+                Response::Values(values) => {
+                    let mut reply = String::new();
+
+                    for (key, value) in keys.iter().zip(values) {
+                        if let Some(value) = value {
+                            let value = value.to_string();
+                            reply += &format!("VALUE {} 0 {}\r\n{}\r\n", key, value.len(), value);
+                        }
+                    }
+
+                    reply + "END"
+                }
+                other => server_error(other),
+            }
+        }
+        Request::Set(key, value) => {
+            let cmd = Command::Set(key, value);
+            let response = run(&cmd);
+            println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+            match response {
+                Response::Ok => "STORED".to_string(),
+                other => server_error(other),
+            }
+        }
+        // a counter never holds more than i64::MAX, so as far as the outcome goes, any larger
+        // delta might as well be i64::MAX: an incr overflows either way, and a decr hits 0
+        Request::Incr(key, delta) => adjust(id, key, delta.min(i64::MAX as u64) as i64, run),
+        Request::Decr(key, delta) => adjust(id, key, -(delta.min(i64::MAX as u64) as i64), run),
+        Request::Delete(key) => {
+            let cmd = Command::Delete(key);
+            let response = run(&cmd);
+            println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+            match response {
+                Response::Ok => "DELETED".to_string(),
+                Response::Error {
+                    code: ErrorCode::NotFound,
+                    ..
+                } => "NOT_FOUND".to_string(),
+                other => server_error(other),
+            }
+        }
+        Request::Version => format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+        Request::Quit => unreachable!("handled by handle_connection"),
+    }
+}
+
+/// run `incr` or `decr`: add `delta` to a counter, and reply with its new value
+///
+/// our Increment and Decrement create missing counters, and Decrement happily goes below 0, so
+/// neither will do. AddUnsigned does exactly what memcached does instead, in a single step
+fn adjust(id: usize, key: String, delta: i64, run: impl Fn(&Command) -> Response) -> String {
+    let cmd = Command::AddUnsigned(key, delta);
+    let response = run(&cmd);
+    println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+    match response {
+        Response::Value(value) => value.to_string(),
+        Response::Error {
+            code: ErrorCode::NotFound,
+            ..
+        } => "NOT_FOUND".to_string(),
+        // memcached's reply for a value that isn't a number it can work with
+        Response::Error {
+            code: ErrorCode::Negative,
+            ..
+        } => "CLIENT_ERROR cannot increment or decrement non-numeric value".to_string(),
+        Response::Error {
+            code: ErrorCode::Overflow,
+            ..
+        } => format!("CLIENT_ERROR incr would take the counter past {}", i64::MAX),
+        other => server_error(other),
+    }
+}
+
+/// memcached's reply to a command line it can't make sense of
+fn bad_format() -> String {
+    "CLIENT_ERROR bad command line format".to_string()
+}
+
+/// build an `InvalidData` error, which is how malformed data blocks are reported
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// translate a `Response` that isn't the expected success into a memcached error
+fn server_error(response: Response) -> String {
+    match response {
+        Response::Error { code, message } => format!("SERVER_ERROR {:?}: {}", code, message),
+        other => format!("SERVER_ERROR unexpected reply: {}", other),
+    }
+}
+
+/// turn away a memcached client with `response`, e.g. because the server is busy
+pub fn reject(stream: &Stream, response: Response) {
+    let _ = send(stream, &server_error(response));
+}
+
+/// write a single reply to the client, adding the line ending
+///
+/// see resp::send
+fn send(mut stream: &Stream, reply: &str) -> io::Result<()> {
+    stream.write_all(format!("{}\r\n", reply).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use client_server::counter::OverflowPolicy;
+    use client_server::store::Store;
+
+    use super::*;
+
+    /// parse a single request out of `input`
+    fn parse(input: &str) -> io::Result<Option<Result<(Request, bool), String>>> {
+        read_request(&mut Cursor::new(input.as_bytes()), 1024)
+    }
+
+    /// the reply to every line of `requests` (a `set`'s data block included), in order, against
+    /// a single fresh store
+    fn replies(requests: &str) -> Vec<String> {
+        let store = Store::new(OverflowPolicy::Wrap);
+        let mut reader = Cursor::new(requests.as_bytes());
+        let mut replies = Vec::new();
+
+        while let Some(request) = read_request(&mut reader, 1024).unwrap() {
+            replies.push(match request {
+                Ok((request, _noreply)) => execute(0, request, |cmd| store.execute(cmd)),
+                Err(error) => error,
+            });
+        }
+
+        replies
+    }
+
+    #[test]
+    fn parses_commands() {
+        match parse("get a b\r\n").unwrap().unwrap() {
+            Ok((Request::Get(keys), false)) => assert_eq!(keys, ["a", "b"]),
+            _ => panic!("expected a get"),
+        }
+
+        match parse("set a 0 0 2\r\n42\r\n").unwrap().unwrap() {
+            Ok((Request::Set(key, 42), false)) => assert_eq!(key, "a"),
+            _ => panic!("expected a set"),
+        }
+
+        match parse("incr a 5 noreply\r\n").unwrap().unwrap() {
+            Ok((Request::Incr(key, 5), true)) => assert_eq!(key, "a"),
+            _ => panic!("expected an incr with noreply"),
+        }
+
+        // get has no noreply; it's just another key
+        match parse("get a noreply\r\n").unwrap().unwrap() {
+            Ok((Request::Get(keys), false)) => assert_eq!(keys, ["a", "noreply"]),
+            _ => panic!("expected a get"),
+        }
+    }
+
+    #[test]
+    fn accepts_any_u64_delta() {
+        match parse("decr a 18446744073709551615\r\n").unwrap().unwrap() {
+            Ok((Request::Decr(_, u64::MAX), false)) => {}
+            _ => panic!("expected a decr by u64::MAX"),
+        }
+
+        assert_eq!(
+            parse("incr a 18446744073709551616\r\n")
+                .unwrap()
+                .unwrap()
+                .err(),
+            Some("CLIENT_ERROR invalid numeric delta argument".to_string())
+        );
+        assert_eq!(
+            parse("incr a -1\r\n").unwrap().unwrap().err(),
+            Some("CLIENT_ERROR invalid numeric delta argument".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_requests() {
+        assert_eq!(
+            parse("bogus\r\n").unwrap().unwrap().err(),
+            Some("ERROR".to_string())
+        );
+        assert_eq!(
+            parse("incr a\r\n").unwrap().unwrap().err(),
+            Some(bad_format())
+        );
+        assert_eq!(
+            parse("set a 0 0 x\r\n").unwrap().unwrap().err(),
+            Some(bad_format())
+        );
+
+        // a data block that doesn't end where its length says it should
+        let e = parse("set a 0 0 1\r\n42\r\n").err().unwrap();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        assert!(parse("").unwrap().is_none());
+    }
+
+    #[test]
+    fn incr_and_decr_follow_memcached() {
+        let replies = replies(
+            "incr a 1\r\n\
+             set a 0 0 2\r\n10\r\n\
+             incr a 5\r\n\
+             decr a 3\r\n\
+             decr a 100\r\n\
+             decr a 18446744073709551615\r\n\
+             get a\r\n",
+        );
+
+        assert_eq!(
+            replies,
+            [
+                "NOT_FOUND",
+                "STORED",
+                "15",
+                "12",
+                "0",
+                "0",
+                "VALUE a 0 1\r\n0\r\nEND"
+            ]
+        );
+    }
+
+    #[test]
+    fn incr_refuses_to_overflow() {
+        let replies = replies(
+            "set a 0 0 19\r\n9223372036854775806\r\n\
+             incr a 1\r\n\
+             incr a 1\r\n\
+             incr a 18446744073709551615\r\n",
+        );
+
+        assert_eq!(replies[..2], ["STORED", "9223372036854775807"]);
+        assert!(replies[2].starts_with("CLIENT_ERROR"));
+        assert!(replies[3].starts_with("CLIENT_ERROR"));
+    }
+
+    #[test]
+    fn negative_counters_are_not_numeric() {
+        let replies = replies(
+            "set a 0 0 2\r\n-5\r\n\
+             incr a 1\r\n\
+             decr a 1\r\n\
+             get a\r\n",
+        );
+
+        let non_numeric = "CLIENT_ERROR cannot increment or decrement non-numeric value";
+        assert_eq!(
+            replies,
+            [
+                "STORED",
+                non_numeric,
+                non_numeric,
+                "VALUE a 0 2\r\n-5\r\nEND"
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_and_get() {
+        let replies = replies(
+            "set a 0 0 1\r\n1\r\n\
+             delete a\r\n\
+             delete a\r\n\
+             get a b\r\n",
+        );
+
+        assert_eq!(replies, ["STORED", "DELETED", "NOT_FOUND", "END"]);
+    }
+}
@@ -0,0 +1,364 @@
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client_server::protocol::{ErrorCode, Response};
+
+use crate::shutdown::{shutting_down, POLL_INTERVAL};
+use crate::transport::Stream;
+use crate::{Protocol, Shared};
+
+/// how often the pool's statistics are logged, provided they've changed
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What the accept loop does with a new connection when every worker is busy and the queue is
+/// full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFull {
+    /// reply with an error and close the connection
+    Reject,
+
+    /// stop accepting until a spot in the queue opens up; new connections pile up in the
+    /// kernel's listen backlog in the meantime
+    Wait,
+}
+
+impl FromStr for OnFull {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reject" => Ok(OnFull::Reject),
+            "wait" => Ok(OnFull::Wait),
+            _ => Err(format!(
+                "unknown on-full behavior '{}', expected one of reject, wait",
+                s
+            )),
+        }
+    }
+}
+
+/// the function a worker calls to serve a connection
+pub type Handler = fn(usize, Stream, Arc<Shared>);
+
+/// the function that tells a client why its connection won't be served, in the client's own
+/// protocol, before it's closed
+pub type Rejecter = fn(&Stream, Response);
+
+/// An accepted connection waiting for a worker
+struct Job {
+    /// connection id, used in log messages
+    id: usize,
+
+    /// the accepted connection
+    stream: Stream,
+
+    /// what the client speaks, and so how to serve it
+    protocol: Protocol,
+}
+
+/// Running totals describing how the pool is keeping up
+#[derive(Debug, Default)]
+pub struct PoolStats {
+    /// connections currently sitting in the queue (or, with `OnFull::Wait`, waiting for room in
+    /// it), waiting for a worker
+    pub queued: AtomicUsize,
+
+    /// workers currently serving a connection
+    pub busy: AtomicUsize,
+
+    /// connections handed to a worker so far
+    pub served: AtomicUsize,
+
+    /// connections turned away because the queue was full
+    pub rejected: AtomicUsize,
+}
+
+impl Display for PoolStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "busy workers: {}, queued: {}, served: {}, rejected: {}",
+            self.busy.load(Ordering::SeqCst),
+            self.queued.load(Ordering::SeqCst),
+            self.served.load(Ordering::SeqCst),
+            self.rejected.load(Ordering::SeqCst)
+        )
+    }
+}
+
+/// A fixed number of worker threads fed accepted connections through a bounded queue
+///
+/// Each worker serves one connection at a time, from start to finish, so at most `workers`
+/// connections are being served at once and at most `queue_size` more are waiting their turn.
+///
+/// Cloning a pool gives another handle to the same queue and workers, e.g. for a second accept
+/// loop.
+#[derive(Clone)]
+pub struct WorkerPool {
+    /// sending half of the queue; the workers share the receiving half
+    sender: SyncSender<Job>,
+
+    /// what to do when the queue is full
+    on_full: OnFull,
+
+    /// see `PoolStats`
+    pub stats: Arc<PoolStats>,
+
+    /// used to stop waiting for room in the queue once a shutdown begins
+    shared: Arc<Shared>,
+
+    /// see `Room`
+    room: Arc<Room>,
+}
+
+/// How many more connections the pool can take on right now: one for each idle worker, plus one
+/// for each free spot in the queue
+///
+/// the channel can't tell us that itself. With `--queue-size 0` it has no spots at all, and a
+/// send only goes through while a worker is already waiting in recv(), so there's no moment
+/// at which "there's room" could be signalled. Counting connections in and out instead gives
+/// `OnFull::Wait` something to sleep on.
+struct Room {
+    /// the count itself
+    free: Mutex<usize>,
+
+    /// signalled whenever a worker is done with a connection, and once a shutdown begins
+    freed: Condvar,
+}
+
+impl Room {
+    /// claim a spot if there is one
+    fn try_take(&self) -> bool {
+        let mut free = self.free.lock().expect("worker pool lock poisoned");
+
+        if *free == 0 {
+            return false;
+        }
+
+        *free -= 1;
+        true
+    }
+
+    /// claim a spot, waiting for one to open up if necessary; gives up (returning false) once
+    /// `stop` returns true
+    ///
+    /// `stop` is checked with the lock held, and `wake_all` takes the lock before signalling,
+    /// so a wakeup can't slip in between the check and going to sleep
+    fn take(&self, stop: impl Fn() -> bool) -> bool {
+        let mut free = self.free.lock().expect("worker pool lock poisoned");
+
+        while *free == 0 {
+            if stop() {
+                return false;
+            }
+
+            free = self.freed.wait(free).expect("worker pool lock poisoned");
+        }
+
+        *free -= 1;
+        true
+    }
+
+    /// hand back a spot, waking one waiting accept loop
+    fn give_back(&self) {
+        *self.free.lock().expect("worker pool lock poisoned") += 1;
+        self.freed.notify_one();
+    }
+
+    /// wake every waiting accept loop, so it can look at its `stop` condition again
+    fn wake_all(&self) {
+        let _free = self.free.lock().expect("worker pool lock poisoned");
+        self.freed.notify_all();
+    }
+}
+
+impl WorkerPool {
+    /// spawn `workers` threads that serve connections from a queue holding up to `queue_size`
+    /// connections
+    pub fn new(workers: usize, queue_size: usize, on_full: OnFull, shared: Arc<Shared>) -> Self {
+        // a sync_channel is a channel with a fixed capacity; once it holds `queue_size` jobs,
+        // send() blocks and try_send() fails until a worker takes one out
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+
+        // there's only one receiving end, so the workers take turns with it behind a mutex
+        let receiver = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(PoolStats::default());
+        let room = Arc::new(Room {
+            free: Mutex::new(workers + queue_size),
+            freed: Condvar::new(),
+        });
+
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let stats = stats.clone();
+            let shared = shared.clone();
+            let room = room.clone();
+
+            thread::spawn(move || worker(receiver, stats, shared, room));
+        }
+
+        let report_stats = stats.clone();
+        let report_ref = shared.clone();
+        thread::spawn(move || report(report_stats, report_ref));
+
+        Self {
+            sender,
+            on_full,
+            stats,
+            shared,
+            room,
+        }
+    }
+
+    /// queue an accepted connection to be served by `protocol`'s handler on the next free worker
+    pub fn submit(&self, id: usize, stream: Stream, protocol: Protocol) {
+        let job = Job {
+            id,
+            stream,
+            protocol,
+        };
+
+        // count the job as queued before it's visible to the workers, otherwise a worker could
+        // pick it up and decrement the count before we increment it
+        self.stats.queued.fetch_add(1, Ordering::SeqCst);
+
+        // waiting only gives up once a shutdown has begun. A blocking send() would go on waiting
+        // after that, and hold up the accept loop (and with it the whole shutdown) until a
+        // worker is done with its connection; with a client that sits idle, that can take as
+        // long as the idle timeout, or forever without one
+        let admitted = match self.on_full {
+            OnFull::Wait => self.room.take(|| self.shared.shutdown.is_requested()),
+            OnFull::Reject => self.room.try_take(),
+        };
+
+        if admitted {
+            // there's an idle worker or a free spot in the queue, so this doesn't wait; at most
+            // for a worker that's done with its last connection to get back to recv()
+            if self.sender.send(job).is_err() {
+                // every worker has exited, which only happens if they all panicked
+                panic!("Every worker thread has exited");
+            }
+
+            return;
+        }
+
+        self.stats.queued.fetch_sub(1, Ordering::SeqCst);
+
+        match self.on_full {
+            OnFull::Wait => {
+                println!(
+                    "[{:7}] server shutting down, dropping waiting connection",
+                    job.id
+                );
+
+                (job.protocol.rejecter())(&job.stream, shutting_down());
+            }
+            OnFull::Reject => {
+                let rejected = self.stats.rejected.fetch_add(1, Ordering::SeqCst) + 1;
+
+                println!(
+                    "[{:7}] server busy, rejecting connection (rejected: {}, queued: {})",
+                    job.id,
+                    rejected,
+                    self.stats.queued.load(Ordering::SeqCst)
+                );
+
+                let response = Response::error(
+                    ErrorCode::ServerBusy,
+                    "server is at its connection limit, try again later",
+                );
+
+                (job.protocol.rejecter())(&job.stream, response);
+            }
+        }
+    }
+
+    /// wake every accept loop that's waiting for room (with `OnFull::Wait`), so it notices that
+    /// a shutdown has begun
+    pub fn wake_waiting(&self) {
+        self.room.wake_all();
+    }
+}
+
+/// body of each worker thread: take the next job off the queue and serve it, forever
+fn worker(
+    receiver: Arc<Mutex<Receiver<Job>>>,
+    stats: Arc<PoolStats>,
+    shared: Arc<Shared>,
+    room: Arc<Room>,
+) {
+    loop {
+        // the lock is only held while waiting for the next job, not while serving it. the guard
+        // is a temporary that's dropped at the end of this statement
+        let job = receiver.lock().expect("worker queue lock poisoned").recv();
+
+        let job = match job {
+            Ok(job) => job,
+            // the sending half is gone, so no more jobs will ever arrive
+            Err(_) => return,
+        };
+
+        stats.queued.fetch_sub(1, Ordering::SeqCst);
+        stats.busy.fetch_add(1, Ordering::SeqCst);
+        stats.served.fetch_add(1, Ordering::SeqCst);
+
+        let Job {
+            id,
+            stream,
+            protocol,
+        } = job;
+        let handler = protocol.handler();
+        let shared = shared.clone();
+
+        // a panicking handler would otherwise take this worker down with it, permanently
+        // shrinking the pool. catch_unwind stops the panic here; the connection is dropped (and
+        // closed) and the worker moves on to the next job
+        let served = panic::catch_unwind(AssertUnwindSafe(|| handler(id, stream, shared)));
+
+        if served.is_err() {
+            eprintln!("[{:7}] handler panicked, connection dropped", id);
+        }
+
+        stats.busy.fetch_sub(1, Ordering::SeqCst);
+
+        // this worker is about to go back to recv(), ready for the next connection
+        room.give_back();
+    }
+}
+
+/// log `stats` every so often until a shutdown is requested, and once more on the way out,
+/// skipping any report that would be the same as the last one
+///
+/// with `OnFull::Wait`, nothing else ever mentions the pool, so this is the only way to tell
+/// that connections are piling up
+fn report(stats: Arc<PoolStats>, shared: Arc<Shared>) {
+    let mut last_report = Instant::now();
+    let mut reported = String::new();
+
+    loop {
+        let stopping = shared.shutdown.is_requested();
+
+        if stopping || last_report.elapsed() >= STATS_INTERVAL {
+            let line = stats.to_string();
+
+            if line != reported {
+                println!("pool stats: {}", line);
+                reported = line;
+            }
+
+            last_report = Instant::now();
+        }
+
+        if stopping {
+            return;
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,532 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::sync::Arc;
+
+use client_server::protocol::{Command, ErrorCode, Response};
+
+use crate::shutdown::shutting_down;
+use crate::timeouts::TimedReader;
+use crate::transport::Stream;
+use crate::Shared;
+
+/// A reply in the Redis serialization protocol (RESP)
+///
+/// RESP marks the type of every value with its first byte: `+` for a simple string, `-` for an
+/// error, `:` for an integer, `$` for a length-prefixed ("bulk") string and `*` for an array.
+/// Every line ends with `\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Reply {
+    /// short, status-style string, e.g. `+OK`
+    Simple(&'static str),
+
+    /// error message, e.g. `-ERR unknown command`
+    Error(String),
+
+    /// signed 64-bit integer
+    Integer(i64),
+
+    /// arbitrary string, or the "null bulk string" when `None`, which redis uses for missing keys
+    Bulk(Option<String>),
+
+    /// list of replies
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    /// append this reply, in wire format, to `out`
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Simple(s) => out.extend_from_slice(format!("+{}\r\n", s).as_bytes()),
+            // an error message can't contain a line break, it would end the reply early
+            Reply::Error(message) => out.extend_from_slice(
+                format!("-{}\r\n", message.replace(&['\r', '\n'][..], " ")).as_bytes(),
+            ),
+            Reply::Integer(n) => out.extend_from_slice(format!(":{}\r\n", n).as_bytes()),
+            Reply::Bulk(Some(s)) => {
+                out.extend_from_slice(format!("${}\r\n{}\r\n", s.len(), s).as_bytes())
+            }
+            Reply::Bulk(None) => out.extend_from_slice(b"$-1\r\n"),
+            Reply::Array(replies) => {
+                out.extend_from_slice(format!("*{}\r\n", replies.len()).as_bytes());
+
+                for reply in replies {
+                    reply.encode(out);
+                }
+            }
+        }
+    }
+}
+
+/// What a single request from a redis client asks for
+enum Request {
+    /// run this Command and translate its Response with the given function
+    Execute(Command, fn(Response) -> Reply),
+
+    /// reply without touching the counters
+    Reply(Reply),
+
+    /// reply, then hang up
+    Quit,
+}
+
+/// Serve a connection from a redis client (redis-cli, redis-benchmark, a client library, ...)
+///
+/// Each request is translated into one of the server's own `Command`s and executed exactly like
+/// a request that arrived over the framed json protocol, against the same counters.
+pub fn handle_connection(id: usize, stream: Stream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+
+    if let Err(e) = stream.set_write_timeout(timeouts.write) {
+        eprintln!("[{:7}] couldn't set write timeout: {}", id, e);
+        return;
+    }
+
+    // redis clients may pipeline, sending many requests before reading any replies. Buffering
+    // reads means a whole batch of small requests is pulled in with a single read() call; the
+    // TimedReader underneath still guards against silent and slow clients
+    let mut reader = BufReader::new(TimedReader::new(&stream, timeouts));
+
+    loop {
+        reader.get_mut().next_message();
+
+        let args = match read_request(&mut reader, shared.config.max_frame_size) {
+            Ok(Some(args)) => args,
+            // the client hung up between requests
+            Ok(None) => return,
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                // redis itself hangs up after a protocol error, since there's no telling where
+                // the next request starts
+                println!("[{:7}] {}; closing connection", id, e);
+                let _ = send(&stream, &Reply::Error(format!("ERR Protocol error: {}", e)));
+                return;
+            }
+            Err(e) => {
+                eprintln!("[{:7}] {}", id, e);
+                return;
+            }
+        };
+
+        let (reply, quit) = match parse(&args) {
+            Request::Execute(cmd, translate) => {
+                // see threaded::handle_connection
+                let _request = match shared.shutdown.begin_request() {
+                    Some(request) => request,
+                    None => {
+                        let _ = send(&stream, &error(shutting_down()));
+                        return;
+                    }
+                };
+
+                let response = shared.execute(&cmd);
+                println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+                (translate(response), false)
+            }
+            Request::Reply(reply) => (reply, false),
+            Request::Quit => (Reply::Simple("OK"), true),
+        };
+
+        if let Err(e) = send(&stream, &reply) {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        if quit || shared.shutdown.is_requested() {
+            return;
+        }
+    }
+}
+
+/// Read a single request: a command name followed by its arguments
+///
+/// Clients normally send an array of bulk strings (`*2\r\n$3\r\nGET\r\n$1\r\nx\r\n`), but a
+/// plain line of space separated words (`GET x\r\n`) is accepted too, so the server can be
+/// poked at with telnet or netcat.
+///
+/// returns `None` when the client closed the connection before starting a new request, and an
+/// `InvalidData` error when the request doesn't follow the protocol
+fn read_request<R: BufRead>(reader: &mut R, max_size: usize) -> io::Result<Option<Vec<String>>> {
+    let line = match read_line(reader, max_size)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    if !line.starts_with('*') {
+        // inline command
+        return Ok(Some(line.split_whitespace().map(String::from).collect()));
+    }
+
+    let count = parse_length(&line[1..])?;
+
+    // each argument takes at least 4 bytes on the wire ("$0\r\n"), so this caps the allocation
+    // below at roughly what max_size allows anyway
+    if count > max_size / 4 {
+        return Err(invalid(format!("too many arguments ({})", count)));
+    }
+
+    let mut args = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let header = read_line(reader, max_size)?
+            .ok_or_else(|| io::Error::from(ErrorKind::UnexpectedEof))?;
+
+        if !header.starts_with('$') {
+            return Err(invalid(format!("expected '$', got '{}'", header)));
+        }
+
+        let len = parse_length(&header[1..])?;
+
+        if len > max_size {
+            return Err(invalid(format!(
+                "argument of {} bytes exceeds maximum of {} bytes",
+                len, max_size
+            )));
+        }
+
+        // the argument, followed by its own \r\n
+        let mut buf = vec![0; len + 2];
+        reader.read_exact(&mut buf)?;
+
+        if !buf.ends_with(b"\r\n") {
+            return Err(invalid("argument isn't followed by \\r\\n"));
+        }
+
+        buf.truncate(len);
+        args.push(String::from_utf8(buf).map_err(|_| invalid("argument isn't valid utf-8"))?);
+    }
+
+    Ok(Some(args))
+}
+
+/// read a single line, without its line ending; `None` at the end of the stream
+pub fn read_line<R: BufRead>(reader: &mut R, max_size: usize) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+
+    // take() puts an upper bound on the line, otherwise a client that never sends a newline
+    // could make us buffer until we run out of memory
+    let read = reader
+        .by_ref()
+        .take(max_size as u64 + 2)
+        .read_until(b'\n', &mut line)?;
+
+    if read == 0 {
+        return Ok(None);
+    }
+
+    if !line.ends_with(b"\n") {
+        return Err(invalid("line too long or incomplete"));
+    }
+
+    // RESP lines end with \r\n, but a bare \n is friendlier to people typing into netcat, and
+    // it's all the text protocol asks for
+    line.pop();
+    if line.ends_with(b"\r") {
+        line.pop();
+    }
+
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| invalid("line isn't valid utf-8"))
+}
+
+/// parse the length that follows `*` or `$`
+fn parse_length(s: &str) -> io::Result<usize> {
+    s.parse()
+        .map_err(|_| invalid(format!("invalid length '{}'", s)))
+}
+
+/// build an `InvalidData` error, which is how malformed requests are reported
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.into())
+}
+
+/// translate a redis request into something we can act on
+fn parse(args: &[String]) -> Request {
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (name.to_uppercase(), args),
+        // an empty request, e.g. a blank line typed into netcat
+        None => return Request::Reply(Reply::Error("ERR empty command".to_string())),
+    };
+
+    match (name.as_str(), args) {
+        ("PING", []) => Request::Execute(Command::Ping, |response| match response {
+            Response::Pong => Reply::Simple("PONG"),
+            other => error(other),
+        }),
+        // redis replies to PING <message> by echoing the message back
+        ("PING", [message]) => Request::Reply(Reply::Bulk(Some(message.clone()))),
+        ("GET", [key]) => Request::Execute(Command::Fetch(key.clone()), |response| {
+            // GET replies with a string, even though our counters are numbers
+            match response {
+                Response::Value(value) => Reply::Bulk(Some(value.to_string())),
+                Response::Error {
+                    code: ErrorCode::NotFound,
+                    ..
+                } => Reply::Bulk(None),
+                other => error(other),
+            }
+        }),
+        ("SET", [key, value]) => match value.parse() {
+            Ok(value) => {
+                Request::Execute(
+                    Command::Set(key.clone(), value),
+                    |response| match response {
+                        Response::Ok => Reply::Simple("OK"),
+                        other => error(other),
+                    },
+                )
+            }
+            // our counters only hold integers
+            Err(_) => Request::Reply(not_an_integer()),
+        },
+        ("INCR", [key]) => update(key, "1", Command::IncrementAndGet),
+        ("DECR", [key]) => update(key, "1", Command::DecrementAndGet),
+        ("INCRBY", [key, amount]) => update(key, amount, Command::IncrementAndGet),
+        ("DECRBY", [key, amount]) => update(key, amount, Command::DecrementAndGet),
+        // redis-cli asks for the command table when it starts up, and redis-benchmark asks for a
+        // couple of config values; an empty answer keeps them both happy
+        ("COMMAND", _) | ("CONFIG", _) => Request::Reply(Reply::Array(Vec::new())),
+        ("QUIT", _) => Request::Quit,
+        ("PING", _)
+        | ("GET", _)
+        | ("SET", _)
+        | ("INCR", _)
+        | ("DECR", _)
+        | ("INCRBY", _)
+        | ("DECRBY", _) => Request::Reply(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_lowercase()
+        ))),
+        _ => Request::Reply(Reply::Error(format!(
+            "ERR unknown command '{}'",
+            name.to_lowercase()
+        ))),
+    }
+}
+
+/// build the request for INCR, DECR, INCRBY and DECRBY
+///
+/// redis replies to all of them with the counter's new value, which is exactly what
+/// IncrementAndGet and DecrementAndGet reply with
+fn update(key: &str, amount: &str, command: fn(String, i64) -> Command) -> Request {
+    let amount = match amount.parse() {
+        Ok(amount) => amount,
+        Err(_) => return Request::Reply(not_an_integer()),
+    };
+
+    Request::Execute(
+        command(key.to_string(), amount),
+        |response| match response {
+            Response::Value(value) => Reply::Integer(value),
+            other => error(other),
+        },
+    )
+}
+
+/// the error redis sends when an argument that should be a number isn't one
+fn not_an_integer() -> Reply {
+    Reply::Error("ERR value is not an integer or out of range".to_string())
+}
+
+/// translate a `Response` that isn't the expected success into a RESP error
+fn error(response: Response) -> Reply {
+    match response {
+        Response::Error { code, message } => Reply::Error(format!("ERR {:?}: {}", code, message)),
+        other => Reply::Error(format!("ERR unexpected reply: {}", other)),
+    }
+}
+
+/// turn away a RESP client with `response`, e.g. because the server is busy
+pub fn reject(stream: &Stream, response: Response) {
+    let _ = send(stream, &error(response));
+}
+
+/// write a single reply to the client
+///
+/// like reading, writing only needs a shared reference, so replies can go out while the reader
+/// holds on to the stream
+fn send(mut stream: &Stream, reply: &Reply) -> io::Result<()> {
+    let mut out = Vec::new();
+    reply.encode(&mut out);
+
+    stream.write_all(&out)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use client_server::counter::OverflowPolicy;
+    use client_server::store::Store;
+
+    use super::*;
+
+    /// read a single request out of `input`
+    fn read(input: &str) -> io::Result<Option<Vec<String>>> {
+        read_request(&mut Cursor::new(input.as_bytes()), 64)
+    }
+
+    /// the reply to each request in `requests`, in order, against a single fresh store
+    fn replies(requests: &[&[&str]]) -> Vec<Reply> {
+        let store = Store::new(OverflowPolicy::Reject);
+
+        requests
+            .iter()
+            .map(|args| {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+
+                match parse(&args) {
+                    Request::Execute(cmd, translate) => translate(store.execute(&cmd)),
+                    Request::Reply(reply) => reply,
+                    Request::Quit => Reply::Simple("BYE"),
+                }
+            })
+            .collect()
+    }
+
+    /// the RESP error message in `reply`
+    fn error_message(reply: &Reply) -> &str {
+        match reply {
+            Reply::Error(message) => message,
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_arrays_of_bulk_strings() {
+        let request = read("*3\r\n$3\r\nSET\r\n$1\r\nx\r\n$2\r\n42\r\n").unwrap();
+        assert_eq!(request.unwrap(), ["SET", "x", "42"]);
+
+        // a bulk string is read by its length, so it may hold spaces and even line breaks
+        let request = read("*2\r\n$3\r\nGET\r\n$4\r\na\r\nb\r\n").unwrap();
+        assert_eq!(request.unwrap(), ["GET", "a\r\nb"]);
+    }
+
+    #[test]
+    fn reads_inline_commands() {
+        assert_eq!(read("GET x\r\n").unwrap().unwrap(), ["GET", "x"]);
+        assert_eq!(read("incr   x\n").unwrap().unwrap(), ["incr", "x"]);
+        assert!(read("\r\n").unwrap().unwrap().is_empty());
+    }
+
+    #[test]
+    fn end_of_stream_before_a_request_is_none() {
+        assert!(read("").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_arrays() {
+        for input in &[
+            // not a number
+            "*x\r\n",
+            // a negative length
+            "*-1\r\n",
+            // an argument that isn't a bulk string
+            "*1\r\n:5\r\n",
+            // a bulk string longer than it claims
+            "*1\r\n$1\r\nab\r\n",
+            // more arguments than could possibly fit
+            "*1000\r\n",
+            // a bulk string larger than the limit
+            "*1\r\n$65\r\n",
+            // a line that never ends
+            &"x".repeat(100),
+        ] {
+            let err = read(input).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", input);
+        }
+
+        // the stream ended part way through the request
+        let err = read("*2\r\n$3\r\nGET\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn executes_counter_commands() {
+        let replies = replies(&[
+            &["set", "x", "5"],
+            &["INCR", "x"],
+            &["DECRBY", "x", "10"],
+            &["incrby", "y", "3"],
+            &["GET", "x"],
+            &["PING"],
+            &["PING", "hello"],
+        ]);
+
+        assert_eq!(
+            replies,
+            [
+                Reply::Simple("OK"),
+                Reply::Integer(6),
+                Reply::Integer(-4),
+                Reply::Integer(3),
+                Reply::Bulk(Some("-4".to_string())),
+                Reply::Simple("PONG"),
+                Reply::Bulk(Some("hello".to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn get_of_a_missing_key_is_a_null_bulk_string() {
+        assert_eq!(replies(&[&["GET", "missing"]]), [Reply::Bulk(None)]);
+
+        let mut out = Vec::new();
+        Reply::Bulk(None).encode(&mut out);
+        assert_eq!(out, b"$-1\r\n");
+    }
+
+    #[test]
+    fn wrong_arity_is_an_error() {
+        for args in &[
+            &["GET"][..],
+            &["GET", "a", "b"],
+            &["SET", "a"],
+            &["INCR"],
+            &["INCRBY", "a"],
+            &["PING", "a", "b"],
+        ] {
+            let reply = &replies(&[args])[0];
+            assert!(
+                error_message(reply).starts_with("ERR wrong number of arguments"),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn other_mistakes_are_errors() {
+        let replies = replies(&[
+            &[],
+            &["FLUSHALL"],
+            &["SET", "x", "abc"],
+            &["INCRBY", "x", "1.5"],
+        ]);
+
+        assert_eq!(error_message(&replies[0]), "ERR empty command");
+        assert_eq!(error_message(&replies[1]), "ERR unknown command 'flushall'");
+        assert!(error_message(&replies[2]).contains("not an integer"));
+        assert!(error_message(&replies[3]).contains("not an integer"));
+    }
+
+    #[test]
+    fn overflow_is_an_error() {
+        let replies = replies(&[&["SET", "x", "9223372036854775807"], &["INCR", "x"]]);
+
+        assert!(error_message(&replies[1]).starts_with("ERR Overflow"));
+    }
+
+    #[test]
+    fn encodes_replies() {
+        let mut out = Vec::new();
+
+        Reply::Array(vec![
+            Reply::Integer(-1),
+            Reply::Bulk(Some("hi".to_string())),
+            Reply::Error("ERR bad\r\nthing".to_string()),
+        ])
+        .encode(&mut out);
+
+        assert_eq!(out, b"*3\r\n:-1\r\n$2\r\nhi\r\n-ERR bad  thing\r\n");
+    }
+}
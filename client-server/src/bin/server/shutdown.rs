@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use client_server::protocol::{ErrorCode, Response};
+
+/// how often accept loops and drain waits check whether anything has changed
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Coordinates a graceful shutdown between the signal handler, accept loops and connection
+/// handlers
+///
+/// Once shutdown is requested, accept loops stop taking new connections and handlers stop
+/// starting new requests. Requests that were already running when the shutdown began are
+/// counted as in flight, and the server waits (up to a deadline) for that count to reach zero
+/// before saving state and exiting.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    /// set once, when the server starts shutting down
+    requested: AtomicBool,
+
+    /// number of requests that have been read and not yet fully replied to
+    in_flight: AtomicUsize,
+}
+
+impl Shutdown {
+    /// begin shutting down; safe to call more than once
+    ///
+    /// returns true if this call is the one that started the shutdown
+    pub fn request(&self) -> bool {
+        !self.requested.swap(true, Ordering::SeqCst)
+    }
+
+    /// true once shutdown has been requested
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// number of requests currently in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// mark the start of a request, returning a guard that marks its end when dropped
+    ///
+    /// returns `None` if the server is shutting down and the request shouldn't be started
+    pub fn begin_request(&self) -> Option<InFlight<'_>> {
+        // the order matters here. by counting the request *before* checking the flag, either we
+        // see the flag and back out, or the shutting down side sees our request in the count and
+        // waits for it. There's no interleaving where both sides miss each other
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        if self.is_requested() {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(InFlight { shutdown: self })
+    }
+
+    /// block until every in-flight request has finished, or until `timeout` has passed
+    ///
+    /// returns false if requests were still running when time ran out
+    pub fn wait_for_drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = self.in_flight();
+
+            if remaining == 0 {
+                return true;
+            }
+
+            if Instant::now() >= deadline {
+                eprintln!(
+                    "gave up waiting for {} in-flight request(s) after {:?}",
+                    remaining, timeout
+                );
+                return false;
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// A request that's in progress; dropping it marks the request as done
+///
+/// tying the count to a value's lifetime means every way out of a handler (early returns,
+/// errors, even panics) ends the request, with nothing to forget
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.shutdown.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// the reply to any request that arrives once the server has started shutting down
+pub fn shutting_down() -> Response {
+    Response::error(
+        ErrorCode::ShuttingDown,
+        "server is shutting down; the command was not executed",
+    )
+}
@@ -0,0 +1,272 @@
+use std::io::{self, BufReader, ErrorKind, Write};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader as AsyncBufReader};
+use tokio::net::TcpStream as AsyncTcpStream;
+use tokio::time;
+
+use client_server::protocol::{Command, Response};
+use client_server::syntax::parse_command;
+
+use crate::asynchronous;
+use crate::resp::read_line;
+use crate::shutdown::shutting_down;
+use crate::timeouts::{AsyncTimedReader, TimedReader};
+use crate::transport::Stream;
+use crate::Shared;
+
+/// What a single line from the client asks for
+enum Line {
+    /// run this Command
+    Execute(Command),
+
+    /// hang up
+    Quit,
+
+    /// nothing but whitespace; ignored
+    Empty,
+}
+
+/// true if a connection whose first byte is `first` speaks the text protocol
+///
+/// every framed message starts with the high byte of its 4-byte length, which is 0 for anything
+/// under 16MiB. Text commands start with a letter, which as a length would mean a frame over a
+/// gigabyte, far beyond anything the server accepts. One byte is enough to tell them apart
+pub fn is_text(first: u8) -> bool {
+    first.is_ascii_alphabetic()
+}
+
+/// wait (up to `idle`) for the first byte of a new connection and report whether the client is
+/// speaking the text protocol
+///
+/// peek() looks at the byte without taking it out of the socket, so whichever protocol handler
+/// ends up serving the connection still gets to read it
+pub fn sniff(stream: &Stream, idle: Option<Duration>) -> io::Result<bool> {
+    let stream = match stream {
+        Stream::Tcp(stream) => stream,
+        // std can't peek at a unix socket (yet), so those only speak the framed protocol
+        #[cfg(unix)]
+        Stream::Unix(_) => return Ok(false),
+    };
+
+    stream.set_read_timeout(idle)?;
+
+    let mut first = [0; 1];
+
+    match stream.peek(&mut first) {
+        // the client hung up without sending anything; the framed handler will notice
+        Ok(0) => Ok(false),
+        Ok(_) => Ok(is_text(first[0])),
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+            Err(idle_error(idle))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// async counterpart of `sniff`, for use with tokio streams
+pub async fn sniff_async(stream: &AsyncTcpStream, idle: Option<Duration>) -> io::Result<bool> {
+    let mut first = [0; 1];
+
+    let peeked = match idle {
+        Some(limit) => time::timeout(limit, stream.peek(&mut first))
+            .await
+            .map_err(|_elapsed| idle_error(idle))?,
+        None => stream.peek(&mut first).await,
+    };
+
+    match peeked? {
+        0 => Ok(false),
+        _ => Ok(is_text(first[0])),
+    }
+}
+
+/// the error reported when a new connection doesn't send anything
+fn idle_error(idle: Option<Duration>) -> io::Error {
+    io::Error::new(
+        ErrorKind::TimedOut,
+        format!(
+            "connection idle for {:?}, closing it",
+            idle.unwrap_or_default()
+        ),
+    )
+}
+
+/// Serve a connection speaking the line-oriented text protocol
+///
+/// Each line is a command, like `INCR 5` or `FETCH visits`, and each gets a single line in
+/// reply, like `OK` or `42`. That's simple enough to type into netcat, or to use from a shell
+/// script:
+///
+/// ```text
+/// exec 3<>/dev/tcp/localhost/4444
+/// echo "INCR 5" >&3
+/// read -r reply <&3
+/// ```
+pub fn handle_connection(id: usize, stream: Stream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+
+    if let Err(e) = stream.set_write_timeout(timeouts.write) {
+        eprintln!("[{:7}] couldn't set write timeout: {}", id, e);
+        return;
+    }
+
+    // buffering lets a script send several lines at once; see resp::handle_connection
+    let mut reader = BufReader::new(TimedReader::new(&stream, timeouts));
+
+    loop {
+        reader.get_mut().next_message();
+
+        let line = match read_line(&mut reader, shared.config.max_frame_size) {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("[{:7}] {}", id, e);
+                return;
+            }
+        };
+
+        let (reply, quit) = respond(id, &line, &shared);
+
+        if let Err(e) = (&stream).write_all(reply.as_bytes()) {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        if quit {
+            return;
+        }
+    }
+}
+
+/// async counterpart of `handle_connection`, for use with tokio streams
+pub async fn handle_async_connection(id: usize, mut stream: AsyncTcpStream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+    let max_line = shared.config.max_frame_size as u64 + 2;
+
+    // split() hands out separate read and write halves of the same socket, so the buffered
+    // reader can hold on to one while replies go out over the other
+    let (mut read_half, mut write_half) = stream.split();
+    let mut reader = AsyncBufReader::new(AsyncTimedReader::new(&mut read_half, timeouts));
+
+    loop {
+        reader.get_mut().next_message();
+
+        // see resp::read_line for why the line is capped
+        let mut line = Vec::new();
+        let read = (&mut reader)
+            .take(max_line)
+            .read_until(b'\n', &mut line)
+            .await;
+
+        match read {
+            Ok(0) => return,
+            Ok(_) if !line.ends_with(b"\n") => {
+                eprintln!("[{:7}] line too long or incomplete", id);
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("[{:7}] {}", id, e);
+                return;
+            }
+        }
+
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(&['\r', '\n'][..]);
+        let (reply, quit) = asynchronous::blocking(|| respond(id, line, &shared));
+
+        let written = match timeouts.write {
+            Some(limit) => time::timeout(limit, write_half.write_all(reply.as_bytes()))
+                .await
+                .unwrap_or_else(|_elapsed| Err(ErrorKind::TimedOut.into())),
+            None => write_half.write_all(reply.as_bytes()).await,
+        };
+
+        if let Err(e) = written {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        if quit {
+            return;
+        }
+    }
+}
+
+/// work out the reply to a single line, and whether to hang up after sending it
+///
+/// the part of serving a connection that doesn't care whether the i/o is blocking or async
+fn respond(id: usize, line: &str, shared: &Shared) -> (String, bool) {
+    let cmd = match parse(line) {
+        Ok(Line::Execute(cmd)) => cmd,
+        Ok(Line::Quit) => return ("BYE\n".to_string(), true),
+        Ok(Line::Empty) => return (String::new(), false),
+        // a bad line doesn't get in the way of the next one, so there's no need to hang up
+        Err(e) => return (format!("ERR {}\n", e), false),
+    };
+
+    // see threaded::handle_connection
+    let _request = match shared.shutdown.begin_request() {
+        Some(request) => request,
+        None => return (format!("{}\n", format(&shutting_down())), true),
+    };
+
+    let response = shared.execute(&cmd);
+    println!("[{:7}] received {:?}; replying with {}", id, cmd, response);
+
+    // the reply is out; rather than wait for another line we'd only refuse, hang up
+    let quit = shared.shutdown.is_requested();
+
+    (format!("{}\n", format(&response)), quit)
+}
+
+/// turn away a text protocol client with `response`, e.g. because the server is busy
+pub fn reject(mut stream: &Stream, response: Response) {
+    let _ = stream.write_all(format!("{}\n", format(&response)).as_bytes());
+}
+
+/// turn a line like `INCR visits 5` into a `Line`
+///
+/// everything but QUIT is the syntax shared with the client's REPL, see
+/// `client_server::syntax::parse_command`
+fn parse(line: &str) -> Result<Line, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+
+    let (verb, args) = match words.split_first() {
+        Some((verb, args)) => (verb.to_uppercase(), args),
+        None => return Ok(Line::Empty),
+    };
+
+    match (verb.as_str(), args) {
+        ("QUIT", []) => Ok(Line::Quit),
+        ("QUIT", _) => Err(format!("wrong number of arguments for {}", verb)),
+        _ => match parse_command(&verb, args) {
+            Some(cmd) => cmd.map(Line::Execute),
+            None => Err(format!("unknown command '{}'", verb)),
+        },
+    }
+}
+
+/// format a `Response` as a single line (without the line ending), easy to pick apart in a
+/// shell script
+pub fn format(response: &Response) -> String {
+    match response {
+        Response::Pong => "PONG".to_string(),
+        Response::Ok => "OK".to_string(),
+        Response::Value(value) => value.to_string(),
+        Response::Values(values) => values
+            .iter()
+            .map(|value| match value {
+                Some(value) => value.to_string(),
+                None => "-".to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        Response::Names(names) => names.join(" "),
+        Response::CasFailed { observed } => format!("CAS_FAILED {}", observed),
+        Response::Batch(responses) => responses.iter().map(format).collect::<Vec<_>>().join(" "),
+        Response::Error { code, message } => format!("ERR {:?} {}", code, message),
+    }
+}
@@ -0,0 +1,213 @@
+use std::sync::Arc;
+use std::thread;
+
+use client_server::protocol::{Message, ProtocolError, Response};
+
+use crate::pool::WorkerPool;
+use crate::shutdown::{shutting_down, POLL_INTERVAL};
+use crate::text;
+use crate::timeouts::TimedReader;
+use crate::transport::{Listener, Stream};
+use crate::{error_response, Protocol, Shared};
+
+/// Accept connections on every listener until a shutdown is requested, handing each one to a
+/// fixed-size pool of worker threads
+///
+/// returns once the accept loops have stopped and in-flight requests have finished, or the
+/// shutdown timeout has run out
+pub fn serve(listeners: Vec<(Listener, Protocol)>, shared: Arc<Shared>) {
+    let pool = WorkerPool::new(
+        shared.config.workers,
+        shared.config.queue_size,
+        shared.config.on_full,
+        shared.clone(),
+    );
+
+    let addresses: Vec<_> = listeners
+        .iter()
+        .filter_map(|(listener, _)| match listener.local_address() {
+            Ok(address) => Some(address),
+            Err(e) => {
+                eprintln!("Couldn't find listener's address: {}", e);
+                None
+            }
+        })
+        .collect();
+
+    // each listener gets its own accept thread; they all feed the same pool
+    let accept_threads: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, protocol)| {
+            let pool = pool.clone();
+            let shared = shared.clone();
+
+            thread::spawn(move || accept_loop(listener, protocol, pool, shared))
+        })
+        .collect();
+
+    while !shared.shutdown.is_requested() {
+        thread::sleep(POLL_INTERVAL);
+    }
+
+    // an accept loop may be waiting for room in the pool (with --on-full wait), rather than in
+    // accept()
+    pool.wake_waiting();
+
+    // the accept loops are blocked waiting for their next client; be that client, so they
+    // notice the shutdown and return
+    for address in &addresses {
+        if let Err(e) = address.poke() {
+            eprintln!("Couldn't wake up accept loop on {:?}: {}", address, e);
+        }
+    }
+
+    for handle in accept_threads {
+        let _ = handle.join();
+    }
+
+    // the workers are still running, finishing whatever requests they were in the middle of
+    shared
+        .shutdown
+        .wait_for_drain(shared.config.shutdown_timeout);
+}
+
+/// accept connections on `listener` until a shutdown is requested, queueing each one to be served
+/// with `protocol`
+fn accept_loop(listener: Listener, protocol: Protocol, pool: WorkerPool, shared: Arc<Shared>) {
+    // accept() blocks until the next client shows up, so a new connection is picked up the
+    // moment it arrives. To stop us, serve() connects once a shutdown has been requested
+    while !shared.shutdown.is_requested() {
+        // a failed accept (e.g. too many open files) only affects that one connection; keep
+        // serving everyone else
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Couldn't accept connection: {}", e);
+                continue;
+            }
+        };
+
+        // the connection that woke us up, or a client that showed up just as we're stopping;
+        // either way it isn't served, and dropping it hangs up
+        if shared.shutdown.is_requested() {
+            break;
+        }
+
+        // the pool hands the stream to the next free worker thread, which calls the protocol's
+        // handler (e.g. handle_connection) with it. unlike spawning a thread per connection, the
+        // number of threads never grows past --workers, no matter how many clients show up
+        pool.submit(shared.next_connection_id(), stream, protocol);
+    }
+}
+
+/// Process established framed-protocol connections to the server and execute tasks based on
+/// the messages sent
+///
+/// The connection is kept open and serves any number of messages, one after another, until the
+/// client closes it or the server starts shutting down.
+///
+/// `stream` defined as mutable for internal state tracking, even during reads
+pub fn handle_connection(id: usize, mut stream: Stream, shared: Arc<Shared>) {
+    let timeouts = shared.config.timeouts;
+
+    // people and scripts may connect here with the text protocol instead; the first byte tells
+    // us which one we're dealing with
+    match text::sniff(&stream, timeouts.idle) {
+        Ok(true) => return text::handle_connection(id, stream, shared),
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("[{:7}] {}", id, e);
+            return;
+        }
+    }
+
+    // a client that stops reading would otherwise leave us blocked in write() once the socket's
+    // send buffer fills up
+    if let Err(e) = stream.set_write_timeout(timeouts.write) {
+        eprintln!("[{:7}] couldn't set write timeout: {}", id, e);
+        return;
+    }
+
+    loop {
+        // pass stream as a reference to the reader. it "borrows" the stream for a bit but gives
+        // ownership back to handle_connection once complete. The reader gives up on clients that
+        // go quiet or send too slowly, so a handler is never stuck waiting on one forever
+        let mut reader = TimedReader::new(&stream, timeouts);
+
+        let msg = match Message::from_stream_with_limit(&mut reader, shared.config.max_frame_size) {
+            Ok(msg) => msg,
+            Err(ProtocolError::Eof) => {
+                // the client hung up between messages; this is the normal way for a session to end
+                return;
+            }
+            Err(e) => {
+                let response = match error_response(&e) {
+                    Some(response) => response,
+                    None => {
+                        // the connection itself is broken, there's nobody left to reply to
+                        eprintln!("[{:7}] {}", id, e);
+                        return;
+                    }
+                };
+
+                // the client sent something we couldn't make sense of; let them know why instead
+                // of silently dropping the connection
+                println!("[{:7}] {}; replying with {}", id, e, response);
+
+                if let Err(e) = response.to_stream(&mut stream) {
+                    eprintln!("[{:7}] couldn't send reply: {}", id, e);
+                    return;
+                }
+
+                // a payload that failed to decode still had a valid length header, so the next
+                // frame starts right where this one ended. An oversized frame was never read
+                // though, so there's no telling where the next frame begins; hang up
+                if let ProtocolError::Oversize { .. } = e {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        // from here until the reply is sent, the request counts as in flight, and a shutdown
+        // waits for it to finish. `_request` marks it as done when it goes out of scope
+        let _request = match shared.shutdown.begin_request() {
+            Some(request) => request,
+            None => {
+                // the message arrived after the shutdown began; let the client know it wasn't
+                // executed, then hang up
+                let response = shutting_down();
+                println!("[{:7}] received {}; replying with {}", id, msg, response);
+
+                let _ = response.to_stream(&mut stream);
+                return;
+            }
+        };
+
+        // Message read and deserialized properly, now we can hand it off to be executed and get
+        // back the matching Response
+        let response = shared.dispatch(&msg);
+
+        println!("[{:7}] received {}; replying with {}", id, msg, response);
+
+        // send serialized response back over the established connection
+        if let Err(e) = response.to_stream(&mut stream) {
+            eprintln!("[{:7}] couldn't send reply: {}", id, e);
+            return;
+        }
+
+        // the reply is out; rather than wait for another message we'd only refuse, hang up
+        if shared.shutdown.is_requested() {
+            return;
+        }
+    }
+}
+
+/// turn away a framed-protocol client with `response`, e.g. because the server is busy
+///
+/// people using the text protocol on the main port get the same frame; telling the two apart
+/// takes waiting for the client's first byte, which the accept loop can't afford to do
+pub fn reject(mut stream: &Stream, response: Response) {
+    // we're hanging up either way, so there's nothing useful to do if this fails
+    let _ = response.to_stream(&mut stream);
+}
@@ -0,0 +1,405 @@
+use std::future::Future;
+use std::io::{self, ErrorKind, Read};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{self, Sleep};
+
+use client_server::protocol::{ProtocolError, Response};
+
+use crate::transport::Stream;
+
+/// how long a message may take to arrive before the minimum receive rate is enforced; a small
+/// message normally arrives all at once, long before this
+const RATE_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Limits on how long the server waits for a client; `None` means no limit
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    /// how long a connection may sit between messages before it's closed
+    pub idle: Option<Duration>,
+
+    /// once a message has started arriving, how long we wait for more of it
+    pub read: Option<Duration>,
+
+    /// how long writing a reply may take before the client is considered gone
+    pub write: Option<Duration>,
+
+    /// slowest average rate (in bytes per second) at which a message may arrive
+    pub min_receive_rate: Option<u64>,
+}
+
+impl Timeouts {
+    /// convert a number of seconds from the command line into a limit, where 0 means no limit
+    pub fn from_secs(secs: u64) -> Option<Duration> {
+        if secs == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(secs))
+        }
+    }
+}
+
+/// Bookkeeping for a single message as it arrives, shared by the blocking and async readers
+///
+/// Until the first byte shows up the connection is idle, and the idle timeout applies. After
+/// that, the read timeout applies to each wait for more data, and the message as a whole must
+/// keep up the minimum receive rate.
+///
+/// A per-read timeout on its own doesn't stop a slowloris-style client, which sends a byte every
+/// few seconds; every single read finishes in time, but the message never does, and the
+/// connection is tied up forever. Checking the average rate catches that.
+struct Progress {
+    timeouts: Timeouts,
+
+    /// when the first byte of the message arrived; `None` while the connection is idle
+    started: Option<Instant>,
+
+    /// bytes of the message received so far
+    received: u64,
+}
+
+impl Progress {
+    fn new(timeouts: Timeouts) -> Self {
+        Self {
+            timeouts,
+            started: None,
+            received: 0,
+        }
+    }
+
+    /// how long the next read may wait for data
+    fn wait_limit(&self) -> Option<Duration> {
+        match self.started {
+            None => self.timeouts.idle,
+            Some(_) => self.timeouts.read,
+        }
+    }
+
+    /// record a read of `n` bytes, failing if the message is now arriving too slowly
+    fn record(&mut self, n: usize) -> io::Result<()> {
+        self.record_at(n, Instant::now())
+    }
+
+    /// `record`, for a read that finished at `now`
+    fn record_at(&mut self, n: usize, now: Instant) -> io::Result<()> {
+        if n == 0 {
+            // end of stream; the protocol layer decides what that means
+            return Ok(());
+        }
+
+        let started = *self.started.get_or_insert(now);
+        self.received += n as u64;
+
+        let min_rate = match self.timeouts.min_receive_rate {
+            Some(min_rate) => min_rate,
+            None => return Ok(()),
+        };
+
+        let elapsed = now.saturating_duration_since(started);
+
+        if elapsed < RATE_GRACE_PERIOD {
+            return Ok(());
+        }
+
+        let rate = self.received as f64 / elapsed.as_secs_f64();
+
+        if rate < min_rate as f64 {
+            return Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!(
+                    "client is sending {:.1} bytes/s, below the minimum of {} bytes/s",
+                    rate, min_rate
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// the error returned when a read waited longer than `wait_limit`
+    fn timed_out(&self) -> io::Error {
+        let limit = self.wait_limit().unwrap_or_default();
+
+        let message = match self.started {
+            None => format!("connection idle for {:?}, closing it", limit),
+            Some(_) => format!(
+                "no data received for {:?} in the middle of a message",
+                limit
+            ),
+        };
+
+        io::Error::new(ErrorKind::TimedOut, message)
+    }
+}
+
+/// Wraps a blocking `Stream` for reading messages, enforcing `Timeouts`
+///
+/// The idle timeout only applies before the first byte of a message. Either make a new reader
+/// for every message, or call `next_message` between messages.
+pub struct TimedReader<'a> {
+    stream: &'a Stream,
+    progress: Progress,
+
+    /// the read timeout currently set on the socket, so we only change it when we need to
+    current: Option<Option<Duration>>,
+}
+
+impl<'a> TimedReader<'a> {
+    pub fn new(stream: &'a Stream, timeouts: Timeouts) -> Self {
+        Self {
+            stream,
+            progress: Progress::new(timeouts),
+            current: None,
+        }
+    }
+
+    /// start timing the next message; until its first byte arrives, the connection is idle
+    pub fn next_message(&mut self) {
+        self.progress = Progress::new(self.progress.timeouts);
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // the socket's read timeout bounds how long a single read() may block; switch it over
+        // from the idle timeout to the read timeout once the message has started
+        let limit = self.progress.wait_limit();
+
+        if self.current != Some(limit) {
+            self.stream.set_read_timeout(limit)?;
+            self.current = Some(limit);
+        }
+
+        // &Stream implements Read, so a shared reference is all we need
+        let mut stream = self.stream;
+
+        match stream.read(buf) {
+            Ok(n) => {
+                self.progress.record(n)?;
+                Ok(n)
+            }
+            // a read that hits the socket's timeout fails with WouldBlock on unix and TimedOut
+            // on windows
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                Err(self.progress.timed_out())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// async counterpart of `TimedReader`, for use with tokio streams
+///
+/// tokio sockets don't have a read timeout setting, so the reader keeps a timer of its own and
+/// gives up once it fires before any data does
+pub struct AsyncTimedReader<'a, R> {
+    stream: &'a mut R,
+    progress: Progress,
+
+    /// fires when the current wait for data has gone on too long; `None` when there's no limit
+    timer: Option<Pin<Box<Sleep>>>,
+}
+
+impl<'a, R> AsyncTimedReader<'a, R> {
+    pub fn new(stream: &'a mut R, timeouts: Timeouts) -> Self {
+        let progress = Progress::new(timeouts);
+
+        // a Sleep has to stay in one place in memory once it's been polled, so it's boxed
+        let timer = progress
+            .wait_limit()
+            .map(|limit| Box::pin(time::sleep(limit)));
+
+        Self {
+            stream,
+            progress,
+            timer,
+        }
+    }
+    /// start timing the next message; see `TimedReader::next_message`
+    pub fn next_message(&mut self) {
+        self.progress = Progress::new(self.progress.timeouts);
+        self.timer = self
+            .progress
+            .wait_limit()
+            .map(|limit| Box::pin(time::sleep(limit)));
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncTimedReader<'_, R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // every field is Unpin, so we're free to get at them through a plain &mut
+        let this = self.get_mut();
+        let before = buf.filled().len();
+
+        match Pin::new(&mut *this.stream).poll_read(cx, buf) {
+            Poll::Ready(Ok(())) => {
+                this.progress.record(buf.filled().len() - before)?;
+
+                // data arrived, so the clock starts over, now with the read timeout
+                this.timer = match (this.progress.wait_limit(), this.timer.take()) {
+                    (Some(limit), Some(mut timer)) => {
+                        timer.as_mut().reset(time::Instant::now() + limit);
+                        Some(timer)
+                    }
+                    (Some(limit), None) => Some(Box::pin(time::sleep(limit))),
+                    (None, _) => None,
+                };
+
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => {
+                // no data yet; polling the timer registers interest in it as well, so we get
+                // woken up by whichever comes first
+                match this.timer.as_mut().map(|timer| timer.as_mut().poll(cx)) {
+                    Some(Poll::Ready(())) => Poll::Ready(Err(this.progress.timed_out())),
+                    _ => Poll::Pending,
+                }
+            }
+        }
+    }
+}
+
+/// send `response` over `stream`, giving up if it takes longer than the write timeout
+///
+/// blocking sockets get the same treatment through `Stream::set_write_timeout`
+pub async fn write_with_timeout<W>(
+    response: &Response,
+    stream: &mut W,
+    timeouts: Timeouts,
+) -> Result<(), ProtocolError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let limit = match timeouts.write {
+        Some(limit) => limit,
+        None => return response.to_async_stream(stream).await,
+    };
+
+    match time::timeout(limit, response.to_async_stream(stream)).await {
+        Ok(result) => result,
+        Err(_elapsed) => Err(ProtocolError::Io(io::Error::new(
+            ErrorKind::TimedOut,
+            format!("client didn't accept the reply within {:?}", limit),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+
+    use super::*;
+
+    fn timeouts(min_receive_rate: Option<u64>) -> Timeouts {
+        Timeouts {
+            idle: Some(Duration::from_secs(60)),
+            read: Some(Duration::from_secs(5)),
+            write: None,
+            min_receive_rate,
+        }
+    }
+
+    fn millis(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn the_idle_timeout_applies_until_the_first_byte() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(None));
+
+        assert_eq!(progress.wait_limit(), Some(Duration::from_secs(60)));
+        assert!(progress.timed_out().to_string().contains("idle"));
+
+        // end of stream doesn't start a message
+        progress.record_at(0, start).unwrap();
+        assert_eq!(progress.wait_limit(), Some(Duration::from_secs(60)));
+
+        progress.record_at(1, start).unwrap();
+        assert_eq!(progress.wait_limit(), Some(Duration::from_secs(5)));
+        assert!(progress
+            .timed_out()
+            .to_string()
+            .contains("middle of a message"));
+    }
+
+    #[test]
+    fn slow_messages_are_allowed_during_the_grace_period() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(Some(1000)));
+
+        progress.record_at(1, start).unwrap();
+        progress.record_at(1, start + millis(999)).unwrap();
+    }
+
+    #[test]
+    fn messages_below_the_minimum_rate_fail_after_the_grace_period() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(Some(100)));
+
+        // 150 bytes in 2 seconds is 75 bytes/s
+        progress.record_at(50, start).unwrap();
+        let e = progress.record_at(100, start + millis(2000)).unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn messages_at_the_minimum_rate_are_fine() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(Some(100)));
+
+        progress.record_at(100, start).unwrap();
+        progress.record_at(100, start + millis(2000)).unwrap();
+    }
+
+    #[test]
+    fn a_slow_drip_is_caught() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(Some(10)));
+
+        // a byte every 500ms; each read arrives well within the read timeout, but the message
+        // as a whole is arriving at 2 bytes/s
+        let drip = (0..10).map(|i| progress.record_at(1, start + millis(500 * i)));
+        let failed_at = drip.take_while(Result::is_ok).count();
+
+        // the first read after the grace period fails
+        assert_eq!(failed_at, 2);
+    }
+
+    #[test]
+    fn without_a_minimum_rate_any_rate_is_fine() {
+        let start = Instant::now();
+        let mut progress = Progress::new(timeouts(None));
+
+        progress.record_at(1, start).unwrap();
+        progress
+            .record_at(1, start + Duration::from_secs(3600))
+            .unwrap();
+    }
+
+    #[test]
+    fn an_idle_connection_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = Stream::Tcp(listener.accept().unwrap().0);
+
+        let mut timeouts = timeouts(None);
+        timeouts.idle = Some(millis(50));
+
+        let e = TimedReader::new(&stream, timeouts)
+            .read(&mut [0; 16])
+            .unwrap_err();
+
+        assert_eq!(e.kind(), ErrorKind::TimedOut);
+        assert!(e.to_string().contains("idle"), "{}", e);
+    }
+}
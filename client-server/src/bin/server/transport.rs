@@ -0,0 +1,278 @@
+#[cfg(unix)]
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::time::Duration;
+
+use socket2::{Domain, Socket, Type};
+
+/// how many connections the kernel holds on to for us while they wait to be accepted; the same
+/// as std's TcpListener::bind
+const BACKLOG: i32 = 128;
+
+/// A bound listener, accepting either tcp or (on unix) unix domain socket connections
+///
+/// The handlers don't care how a client reached us, so everything past `accept` deals in
+/// `Stream`s and works the same for both.
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// bind a tcp listener to `addr`
+    ///
+    /// an IPv6 listener only accepts IPv6 clients; see `socket_for`
+    pub fn bind_tcp(addr: SocketAddr) -> io::Result<Self> {
+        let socket = socket_for(addr, Type::STREAM)?;
+
+        // like TcpListener::bind, let a restarted server reuse the port right away, instead of
+        // waiting for the previous run's connections to finish closing
+        socket.set_reuse_address(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(BACKLOG)?;
+
+        Ok(Listener::Tcp(socket.into()))
+    }
+
+    /// create a unix domain socket at `path` and listen on it, with `mode` (e.g. 0o660) as its
+    /// file permissions
+    ///
+    /// connecting to a unix socket takes write permission on its file, so the usual owner,
+    /// group and other bits decide who may talk to the server; no port is exposed to anyone
+    #[cfg(unix)]
+    pub fn bind_unix(path: &Path, mode: u32) -> io::Result<Self> {
+        remove_stale_socket(path)?;
+
+        // until set_permissions, the socket has whatever permissions the umask leaves it with,
+        // which normally keeps everyone but the owner from connecting anyway
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+
+        Ok(Listener::Unix(listener))
+    }
+
+    /// unix domain sockets only exist on unix; everywhere else, asking for one is an error
+    #[cfg(not(unix))]
+    pub fn bind_unix(path: &Path, _mode: u32) -> io::Result<Self> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "can't listen on {}: unix sockets aren't supported here",
+                path.display()
+            ),
+        ))
+    }
+
+    /// wait for the next connection
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _addr)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _addr)| Stream::Unix(stream)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// where a client on this machine can connect to reach the listener
+    pub fn local_address(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(listener) => {
+                let mut addr = listener.local_addr()?;
+
+                // nobody can connect to 0.0.0.0 or ::, but a listener bound to every address is
+                // also bound to loopback
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr.ip() {
+                        IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+
+                Ok(Address::Tcp(addr))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(Address::Unix(path.to_path_buf())),
+                None => Err(io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    "unix socket has no path",
+                )),
+            },
+        }
+    }
+}
+
+/// Where a `Listener` can be reached
+#[derive(Debug, Clone)]
+pub enum Address {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// connect, then hang up right away
+    ///
+    /// a thread blocked in `Listener::accept` has no other way to find out that it's time to
+    /// stop; this gets accept() to return, and the thread to look around
+    pub fn poke(&self) -> io::Result<()> {
+        match self {
+            Address::Tcp(addr) => {
+                TcpStream::connect_timeout(addr, Duration::from_secs(1)).map(drop)
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => UnixStream::connect(path).map(drop),
+        }
+    }
+}
+
+/// An accepted connection, over either transport
+///
+/// Like the std types it wraps, `Stream` and `&Stream` both implement `Read` and `Write`.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+        }
+    }
+}
+
+// std implements Read and Write for &TcpStream and &UnixStream, so a shared reference to a
+// socket is all it takes to use it. `stream` below is one of those shared references; `&*stream`
+// makes a copy of it that read() and write() can borrow mutably
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+/// bind a udp socket to `addr`
+///
+/// like `Listener::bind_tcp`, an IPv6 socket only receives IPv6 datagrams
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = socket_for(addr, Type::DGRAM)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// create an unbound socket of type `kind` for `addr`'s address family
+///
+/// On Linux, an IPv6 socket bound to `::` also takes IPv4 traffic by default, which claims the
+/// port on 0.0.0.0 too, so `--listen 0.0.0.0 --listen ::` would fail with "address in use".
+/// Turning on IPV6_V6ONLY keeps each socket to its own family, which makes listening on both
+/// work, and makes `--listen ::` mean the same thing everywhere: IPv6 only
+fn socket_for(addr: SocketAddr, kind: Type) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), kind, None)?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    Ok(socket)
+}
+
+/// remove whatever a previous run left at `path`, provided it's a socket nobody's listening on
+///
+/// a unix socket's file outlives the process that created it unless it's removed on the way
+/// out, which doesn't happen after a crash. Binding fails while the file exists, so without
+/// this, the server would refuse to start again until somebody deleted it by hand
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    // never delete something that isn't ours to delete
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()),
+        ));
+    }
+
+    // a successful connection means another server is alive and well behind it
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("another server is already listening on {}", path.display()),
+        ));
+    }
+
+    fs::remove_file(path)
+}
@@ -0,0 +1,364 @@
+use std::fmt::{Display, Formatter};
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use client_server::protocol::{Command, Message, Response};
+
+use crate::shutdown::POLL_INTERVAL;
+use crate::Shared;
+
+/// largest payload a udp datagram can carry over IPv4; anything bigger never reaches us whole
+const MAX_DATAGRAM_SIZE: usize = 65_507;
+
+/// how often a socket's statistics are logged, provided they've changed
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Running totals for a single udp socket
+///
+/// each socket is read by a single thread, so unlike `PoolStats` these don't need to be atomic
+#[derive(Debug, Default)]
+struct UdpStats {
+    /// datagrams read from the socket
+    received: u64,
+
+    /// commands applied to the counters; a compact datagram can carry several
+    applied: u64,
+
+    /// datagrams that couldn't be parsed, or were too large
+    malformed: u64,
+
+    /// commands from well-formed datagrams that weren't applied: a command other than Increment
+    /// or Decrement, an update the store refused (e.g. an overflow with `--overflow reject`), or
+    /// one that arrived while the server was shutting down
+    dropped: u64,
+}
+
+impl Display for UdpStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "received: {}, applied: {}, malformed: {}, dropped: {}",
+            self.received, self.applied, self.malformed, self.dropped
+        )
+    }
+}
+
+/// Apply the Increment and Decrement commands that arrive on `socket` until a shutdown is
+/// requested
+///
+/// udp is fire-and-forget: nothing is ever sent back, so a client can push out updates as fast
+/// as it likes without waiting on a round trip. The price is that nobody finds out about an
+/// update that went missing, which makes it a good fit for metrics and a poor one for anything
+/// that has to add up exactly.
+///
+/// Each datagram holds either
+///
+/// - a json `Message`, exactly like the payload of a framed message (without the length header,
+///   since a datagram has a length of its own), e.g. `{"cmd":{"Increment":["hits",1]}}`
+/// - one or more lines in a compact, statsd-style format: `name:amount`, optionally followed by
+///   statsd's `|c` counter type. A positive amount increments, a negative one decrements, e.g.
+///   `hits:1` or `queue:-3|c`
+pub fn serve(socket: UdpSocket, shared: Arc<Shared>) {
+    let addr = socket
+        .local_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "udp".to_string());
+
+    // like the accept loops, a read that gives up every so often lets us notice a shutdown
+    socket
+        .set_read_timeout(Some(POLL_INTERVAL))
+        .expect("Couldn't set udp read timeout");
+
+    let mut stats = UdpStats::default();
+    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+    let mut last_report = Instant::now();
+    let mut reported = String::new();
+
+    while !shared.shutdown.is_requested() {
+        if last_report.elapsed() >= STATS_INTERVAL {
+            report(&addr, &socket, &stats, &mut reported);
+            last_report = Instant::now();
+        }
+
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                continue
+            }
+            Err(e) => {
+                eprintln!("[{}] couldn't receive datagram: {}", addr, e);
+                continue;
+            }
+        };
+
+        stats.received += 1;
+
+        let cmds = match parse(&buf[..len], shared.config.max_frame_size) {
+            Ok(cmds) => cmds,
+            Err(e) => {
+                stats.malformed += 1;
+                println!("[{}] malformed datagram: {}", addr, e);
+                continue;
+            }
+        };
+
+        apply(&cmds, &shared, &mut stats);
+    }
+
+    report(&addr, &socket, &stats, &mut reported);
+}
+
+/// turn a datagram into the commands it carries
+///
+/// every line is checked before any of them is applied, so a single bad line makes the whole
+/// datagram malformed
+fn parse(datagram: &[u8], max_size: usize) -> Result<Vec<Command>, String> {
+    if datagram.len() > max_size {
+        return Err(format!(
+            "{} bytes exceeds maximum of {} bytes",
+            datagram.len(),
+            max_size
+        ));
+    }
+
+    // a json Message always starts with '{', which can't start a compact line
+    let first = datagram.iter().find(|b| !b.is_ascii_whitespace());
+
+    if first == Some(&b'{') {
+        let msg = Message::from_slice(datagram).map_err(|e| e.to_string())?;
+
+        return msg
+            .cmd
+            .map(|cmd| vec![cmd])
+            .ok_or_else(|| "message has no command".to_string());
+    }
+
+    let text = std::str::from_utf8(datagram).map_err(|_| "datagram isn't valid utf-8")?;
+
+    let cmds = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(parse_compact)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if cmds.is_empty() {
+        return Err("empty datagram".to_string());
+    }
+
+    Ok(cmds)
+}
+
+/// parse a single `name:amount[|c]` line
+fn parse_compact(line: &str) -> Result<Command, String> {
+    let (name, value) = line
+        .rsplit_once(':')
+        .ok_or_else(|| format!("expected name:amount, got '{}'", line))?;
+
+    // statsd marks counters with |c; other metric types don't mean anything to us
+    let amount = match value.split_once('|') {
+        Some((amount, "c")) => amount,
+        Some((_, kind)) => return Err(format!("unsupported metric type '{}'", kind)),
+        None => value,
+    };
+
+    let (decrement, digits) = match amount.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, amount.strip_prefix('+').unwrap_or(amount)),
+    };
+
+    // checked by hand, since i64's parser would also take a second sign, e.g. "--5"
+    if name.is_empty() || digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(format!("expected name:amount, got '{}'", line));
+    }
+
+    let digits: i64 = digits
+        .parse()
+        .map_err(|_| format!("amount '{}' is out of range", amount))?;
+
+    if decrement {
+        Ok(Command::Decrement(name.to_string(), digits))
+    } else {
+        Ok(Command::Increment(name.to_string(), digits))
+    }
+}
+
+/// apply the commands from a single datagram, updating `stats` along the way
+fn apply(cmds: &[Command], shared: &Shared, stats: &mut UdpStats) {
+    // nobody's listening for a reply, so anything that would need one has no business here
+    let fire_and_forget = cmds
+        .iter()
+        .all(|cmd| matches!(cmd, Command::Increment(..) | Command::Decrement(..)));
+
+    if !fire_and_forget {
+        stats.dropped += cmds.len() as u64;
+        return;
+    }
+
+    // see threaded::handle_connection
+    let _request = match shared.shutdown.begin_request() {
+        Some(request) => request,
+        None => {
+            stats.dropped += cmds.len() as u64;
+            return;
+        }
+    };
+
+    // each line is applied on its own rather than as a Batch: a batch takes the store's write
+    // lock and copies every counter, which is far too much to pay for each datagram on what's
+    // meant to be the cheapest way in. A line the store refuses (e.g. an overflow) is dropped
+    // without holding up the others
+    for cmd in cmds {
+        match shared.execute(cmd) {
+            Response::Error { .. } => stats.dropped += 1,
+            _ => stats.applied += 1,
+        }
+    }
+}
+
+/// log `stats` for the socket at `addr`, unless nothing has changed since the last time
+fn report(addr: &str, socket: &UdpSocket, stats: &UdpStats, reported: &mut String) {
+    let mut line = stats.to_string();
+
+    if let Some(drops) = kernel_drops(socket) {
+        line += &format!(", dropped by the kernel: {}", drops);
+    }
+
+    if line != *reported {
+        println!("[{}] udp stats: {}", addr, line);
+        *reported = line;
+    }
+}
+
+/// number of datagrams the kernel dropped for `socket` because we didn't read them fast enough
+///
+/// those never reach us, so they don't show up in `UdpStats`. Linux counts them per socket in
+/// /proc/net/udp (and udp6), keyed by the socket's inode
+#[cfg(target_os = "linux")]
+fn kernel_drops(socket: &UdpSocket) -> Option<u64> {
+    // /proc/self/fd/<fd> is a symlink to "socket:[<inode>]"
+    let link = fs::read_link(format!("/proc/self/fd/{}", socket.as_raw_fd())).ok()?;
+    let inode = link
+        .to_str()?
+        .strip_prefix("socket:[")?
+        .strip_suffix(']')?
+        .to_string();
+
+    for table in &["/proc/net/udp", "/proc/net/udp6"] {
+        let contents = match fs::read_to_string(table) {
+            Ok(contents) => contents,
+            Err(_) => continue,
+        };
+
+        // after a header line, one line per socket; the inode is the 10th column and the drop
+        // count the last
+        for line in contents.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+
+            if fields.get(9) == Some(&inode.as_str()) {
+                return fields.last()?.parse().ok();
+            }
+        }
+    }
+
+    None
+}
+
+/// other systems don't keep a per-socket count we can get at, so the drops go unreported
+#[cfg(not(target_os = "linux"))]
+fn kernel_drops(_socket: &UdpSocket) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 1024;
+
+    /// parse `datagram` and show the result with Debug, since Command can't be compared with ==
+    fn parsed(datagram: &str) -> String {
+        format!("{:?}", parse(datagram.as_bytes(), MAX))
+    }
+
+    #[test]
+    fn the_sign_picks_the_command() {
+        assert_eq!(parsed("hits:5"), r#"Ok([Increment("hits", 5)])"#);
+        assert_eq!(parsed("hits:+5"), r#"Ok([Increment("hits", 5)])"#);
+        assert_eq!(parsed("queue:-3"), r#"Ok([Decrement("queue", 3)])"#);
+    }
+
+    #[test]
+    fn counters_may_be_marked_with_c() {
+        assert_eq!(parsed("hits:1|c"), r#"Ok([Increment("hits", 1)])"#);
+        assert_eq!(
+            parsed("hits:1|c\n\n  queue:-2|c  \n"),
+            r#"Ok([Increment("hits", 1), Decrement("queue", 2)])"#
+        );
+    }
+
+    #[test]
+    fn names_may_contain_colons() {
+        assert_eq!(parsed("a:b:1"), r#"Ok([Increment("a:b", 1)])"#);
+    }
+
+    #[test]
+    fn other_metric_types_are_refused() {
+        for line in &["latency:320|ms", "temperature:20|g", "users:42|s"] {
+            assert!(parse_compact(line).is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_lines_are_refused() {
+        for line in &[
+            "hits", ":1", "hits:", "hits:--5", "hits:+-5", "hits:1.5", "hits: 1",
+        ] {
+            assert!(parse_compact(line).is_err(), "{}", line);
+        }
+
+        assert!(parse_compact("hits:9223372036854775808").is_err());
+        assert!(parse_compact("hits:9223372036854775807").is_ok());
+    }
+
+    #[test]
+    fn one_bad_line_spoils_the_datagram() {
+        assert!(parse(b"hits:1\nbogus\nqueue:-1", MAX).is_err());
+    }
+
+    #[test]
+    fn empty_datagrams_are_refused() {
+        assert!(parse(b"", MAX).is_err());
+        assert!(parse(b" \n\n ", MAX).is_err());
+    }
+
+    #[test]
+    fn json_datagrams_hold_a_message() {
+        assert_eq!(
+            parsed(r#" {"cmd":{"Increment":["hits",2]}}"#),
+            r#"Ok([Increment("hits", 2)])"#
+        );
+        assert!(parse(b"{\"cmd\":", MAX).is_err());
+        assert!(parse(b"{}", MAX).is_err());
+    }
+
+    #[test]
+    fn oversized_datagrams_are_refused() {
+        let datagram = format!("hits:{}", "1".repeat(MAX));
+
+        assert!(parse(datagram.as_bytes(), MAX).is_err());
+        assert!(parse(b"hits:1", 6).is_ok());
+        assert!(parse(b"hits:10", 6).is_err());
+    }
+
+    #[test]
+    fn invalid_utf8_is_refused() {
+        assert!(parse(b"hits:1\xff", MAX).is_err());
+    }
+}
@@ -0,0 +1,470 @@
+use std::fmt::{Display, Formatter};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::protocol::{Command, ErrorCode, Message, ProtocolError, Response};
+
+/// Everything that can go wrong while sending a command to the server
+#[derive(Debug)]
+pub enum ClientError {
+    /// couldn't establish a connection to the server
+    Connect(io::Error),
+
+    /// the connection broke, or the server's reply couldn't be read
+    Protocol(ProtocolError),
+
+    /// the server received the command and replied with an error
+    Server { code: ErrorCode, message: String },
+
+    /// the server replied with something that doesn't answer the command that was sent
+    Unexpected(Response),
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Connect(e) => write!(f, "couldn't connect to server: {}", e),
+            ClientError::Protocol(e) => write!(f, "{}", e),
+            ClientError::Server { code, message } => {
+                write!(f, "server error ({:?}): {}", code, message)
+            }
+            ClientError::Unexpected(response) => write!(f, "unexpected reply: {}", response),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ClientError::Connect(e) => Some(e),
+            ClientError::Protocol(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<ProtocolError> for ClientError {
+    fn from(e: ProtocolError) -> Self {
+        ClientError::Protocol(e)
+    }
+}
+
+/// Where the server is listening
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    /// a tcp address, as host:port
+    Tcp(String),
+
+    /// the path of a unix domain socket, for a server on the same machine
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Target::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+// a plain string is a host:port, so `Client::new("127.0.0.1:4444")` keeps working
+impl From<&str> for Target {
+    fn from(addr: &str) -> Self {
+        Target::Tcp(addr.to_string())
+    }
+}
+
+impl From<String> for Target {
+    fn from(addr: String) -> Self {
+        Target::Tcp(addr)
+    }
+}
+
+/// An open connection to the server, over whichever transport the `Target` called for
+#[derive(Debug)]
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Settings that control how a `Client` connects and retries
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// how long to wait for a connection to be established
+    pub connect_timeout: Duration,
+
+    /// how long to wait for the server to accept a command, and again for its reply
+    pub timeout: Duration,
+
+    /// most idle connections kept around for reuse; 0 opens a new connection for every command
+    pub pool_size: usize,
+
+    /// how many times a failed command is retried before the error is returned
+    pub max_retries: u32,
+
+    /// wait before the first retry; doubled for every retry after that
+    pub initial_backoff: Duration,
+
+    /// upper bound on the wait between retries
+    pub max_backoff: Duration,
+
+    /// largest reply (in bytes) the client will read. The server's `--max-frame-size` only
+    /// limits what clients send; a reply to `List` or `FetchMany` grows with the number of
+    /// counters, so this is much larger by default
+    pub max_reply_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(3),
+            timeout: Duration::from_secs(5),
+            pool_size: 16,
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            max_reply_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// A connection to the counter server
+///
+/// `Client` keeps a pool of open connections, so commands after the first don't pay for a
+/// handshake. It's `Sync`, so a single `Client` can be shared by any number of threads; each
+/// command borrows a connection from the pool for as long as it takes to get the reply.
+///
+/// Commands that fail before they reach the server (the connection couldn't be made, or the
+/// server was too busy to take it) are retried with exponential backoff. Commands that fail
+/// after they were sent are only retried when they're idempotent (see `Command::is_idempotent`);
+/// retrying an `Increment` whose reply got lost could count it twice.
+///
+/// ```no_run
+/// use client_server::Client;
+///
+/// let client = Client::new("127.0.0.1:4444");
+///
+/// client.increment("visitors", 1)?;
+/// println!("visitors: {}", client.fetch("visitors")?);
+/// # Ok::<(), client_server::client::ClientError>(())
+/// ```
+///
+/// On unix, a server on the same machine can be reached over a unix domain socket instead,
+/// which skips the tcp stack entirely:
+///
+/// ```no_run
+/// # #[cfg(unix)] {
+/// use client_server::client::Target;
+/// use client_server::Client;
+///
+/// let client = Client::new(Target::Unix("/tmp/counter.sock".into()));
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    /// where the server is listening
+    target: Target,
+
+    config: ClientConfig,
+
+    /// connections that aren't in use by any command right now
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl Client {
+    /// create a client for the server at `target` (a host:port, or a `Target`) with the default
+    /// `ClientConfig`
+    ///
+    /// no connection is made until the first command is sent
+    pub fn new(target: impl Into<Target>) -> Self {
+        Client::with_config(target, ClientConfig::default())
+    }
+
+    /// create a client for the server at `target` (a host:port, or a `Target`) with the given
+    /// settings
+    pub fn with_config(target: impl Into<Target>, config: ClientConfig) -> Self {
+        Self {
+            target: target.into(),
+            config,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// check that the server is up and responding
+    pub fn ping(&self) -> Result<(), ClientError> {
+        match self.execute(&Command::Ping)? {
+            Response::Pong => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// add `amount` to the named counter
+    pub fn increment(&self, name: &str, amount: i64) -> Result<(), ClientError> {
+        match self.execute(&Command::Increment(name.to_string(), amount))? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// subtract `amount` from the named counter
+    pub fn decrement(&self, name: &str, amount: i64) -> Result<(), ClientError> {
+        match self.execute(&Command::Decrement(name.to_string(), amount))? {
+            Response::Ok => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// get the current value of the named counter
+    pub fn fetch(&self, name: &str) -> Result<i64, ClientError> {
+        match self.execute(&Command::Fetch(name.to_string()))? {
+            Response::Value(value) => Ok(value),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// send any `Command` and return the server's reply, retrying where it's safe to
+    ///
+    /// the reply is returned as-is, so an error the server sends back is `Ok(Response::Error)`
+    /// here; only a reply that never arrived is an `Err`
+    pub fn execute(&self, cmd: &Command) -> Result<Response, ClientError> {
+        let mut attempt = 0;
+
+        loop {
+            let (result, sent) = self.attempt(cmd);
+
+            // a connection that was never made, or a server that turned us away before reading
+            // anything, never saw the command. Those are safe to retry no matter what the
+            // command does
+            let retryable = match &result {
+                Ok(Response::Error { code, .. }) => *code == ErrorCode::ServerBusy,
+                Ok(_) => false,
+                // asking again would only get the same reply, which would be just as large
+                Err(ClientError::Protocol(ProtocolError::Oversize { .. })) => false,
+                Err(_) => !sent || cmd.is_idempotent(),
+            };
+
+            if !retryable || attempt >= self.config.max_retries {
+                return result;
+            }
+
+            thread::sleep(self.backoff(attempt));
+            attempt += 1;
+        }
+    }
+
+    /// send `cmd` once, over a pooled connection if there is one
+    ///
+    /// along with the result, returns whether the command made it onto the wire
+    fn attempt(&self, cmd: &Command) -> (Result<Response, ClientError>, bool) {
+        let mut stream = match self.checkout() {
+            Ok(stream) => stream,
+            Err(e) => return (Err(ClientError::Connect(e)), false),
+        };
+
+        let msg = Message {
+            cmd: Some(cmd.clone()),
+            body: None,
+        };
+
+        if let Err(e) = msg.to_stream(&mut stream) {
+            // the write may have made it out before failing; assume the worst
+            return (Err(e.into()), true);
+        }
+
+        match Response::from_stream_with_limit(&mut stream, self.config.max_reply_size) {
+            Ok(response) => {
+                // a server that's busy or shutting down hangs up after replying, so only a
+                // connection that's still in good standing goes back into the pool
+                match response {
+                    Response::Error {
+                        code: ErrorCode::ServerBusy,
+                        ..
+                    }
+                    | Response::Error {
+                        code: ErrorCode::ShuttingDown,
+                        ..
+                    } => {}
+                    _ => self.checkin(stream),
+                }
+
+                (Ok(response), true)
+            }
+            Err(e) => (Err(e.into()), true),
+        }
+    }
+
+    /// take an idle connection out of the pool, or open a new one if there isn't a usable one
+    fn checkout(&self) -> io::Result<Connection> {
+        loop {
+            // the guard is a temporary, so the lock is released at the end of this statement
+            let pooled = self
+                .idle
+                .lock()
+                .expect("connection pool lock poisoned")
+                .pop();
+
+            match pooled {
+                Some(stream) if is_open(&stream) => return Ok(stream),
+                // the server closed this one while it sat in the pool (e.g. its idle timeout
+                // ran out); throw it away and try the next
+                Some(_) => continue,
+                None => return self.connect(),
+            }
+        }
+    }
+
+    /// put a connection back in the pool, or close it if the pool is full
+    fn checkin(&self, stream: Connection) {
+        let mut idle = self.idle.lock().expect("connection pool lock poisoned");
+
+        if idle.len() < self.config.pool_size {
+            idle.push(stream);
+        }
+    }
+
+    /// open a new connection to the server, applying the configured timeouts
+    fn connect(&self) -> io::Result<Connection> {
+        let addr = match &self.target {
+            Target::Tcp(addr) => addr,
+            #[cfg(unix)]
+            Target::Unix(path) => {
+                // connecting to a local socket either works or fails right away, there's no
+                // network to wait on, so there's no connect timeout to apply
+                let stream = UnixStream::connect(path)?;
+                stream.set_read_timeout(Some(self.config.timeout))?;
+                stream.set_write_timeout(Some(self.config.timeout))?;
+                return Ok(Connection::Unix(stream));
+            }
+        };
+
+        // unlike TcpStream::connect, connect_timeout only takes a single, already resolved
+        // address. A hostname can resolve to several (e.g. an IPv6 and an IPv4 one); try each
+        let mut last_error = None;
+
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.config.connect_timeout) {
+                Ok(stream) => {
+                    // reads and writes fail once they've waited this long, so a server that
+                    // stops responding can't hang the caller
+                    stream.set_read_timeout(Some(self.config.timeout))?;
+                    stream.set_write_timeout(Some(self.config.timeout))?;
+                    return Ok(Connection::Tcp(stream));
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} didn't resolve to any address", addr),
+            )
+        }))
+    }
+
+    /// how long to wait before retry number `attempt` (counting from 0)
+    fn backoff(&self, attempt: u32) -> Duration {
+        // 2^attempt quickly gets out of hand; past 2^16 we're well beyond any sane max_backoff
+        let factor = 2u32.saturating_pow(attempt.min(16));
+        let delay = self
+            .config
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.config.max_backoff);
+
+        // add up to 50% of random jitter, so a crowd of clients that failed at the same moment
+        // doesn't come back at the same moment too
+        let jitter = rand::thread_rng().gen_range(0.0..0.5);
+
+        delay + delay.mul_f64(jitter)
+    }
+}
+
+/// turn a reply that doesn't answer the command into the matching `ClientError`
+fn unexpected(response: Response) -> ClientError {
+    match response {
+        Response::Error { code, message } => ClientError::Server { code, message },
+        other => ClientError::Unexpected(other),
+    }
+}
+
+/// true if the server hasn't closed `connection` from its end
+///
+/// a closed connection reads as end-of-stream right away; an open, idle one has nothing to read
+/// and would block. Switching to non-blocking mode lets us tell the two apart without waiting
+fn is_open(connection: &Connection) -> bool {
+    let mut byte = [0; 1];
+
+    let (read, restored) = match connection {
+        Connection::Tcp(stream) => {
+            if stream.set_nonblocking(true).is_err() {
+                return false;
+            }
+
+            (stream.peek(&mut byte), stream.set_nonblocking(false))
+        }
+        #[cfg(unix)]
+        Connection::Unix(stream) => {
+            if stream.set_nonblocking(true).is_err() {
+                return false;
+            }
+
+            // std can't peek at a unix socket, but a connection with anything to read gets
+            // thrown away anyway, so actually reading the byte does no harm. &UnixStream
+            // implements Read, so a shared reference is all we need
+            let mut reader = stream;
+
+            (reader.read(&mut byte), stream.set_nonblocking(false))
+        }
+    };
+
+    let open = match read {
+        Err(e) => e.kind() == ErrorKind::WouldBlock,
+        // Ok(0) is a closed connection; anything else is data nobody asked for, which would be
+        // mistaken for the reply to the next command
+        Ok(_) => false,
+    };
+
+    restored.is_ok() && open
+}
@@ -0,0 +1,284 @@
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+/// What the counter should do when an update would move it past `i64::MAX` or `i64::MIN`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// two's complement wrap-around, i.e. `i64::MAX + 1 == i64::MIN`
+    Wrap,
+
+    /// clamp the result to `i64::MAX` or `i64::MIN`
+    Saturate,
+
+    /// leave the counter untouched and report an error
+    Reject,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    /// allows `"wrap".parse::<OverflowPolicy>()`, which is handy when parsing command line args
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "wrap" => Ok(OverflowPolicy::Wrap),
+            "saturate" => Ok(OverflowPolicy::Saturate),
+            "reject" => Ok(OverflowPolicy::Reject),
+            _ => Err(format!(
+                "unknown overflow policy '{}', expected one of wrap, saturate, reject",
+                s
+            )),
+        }
+    }
+}
+
+/// Returned when an update is rejected under `OverflowPolicy::Reject`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OverflowError {
+    /// the counter's value when the update was attempted
+    pub current: i64,
+
+    /// the amount the update tried to add (negative for a decrement)
+    pub delta: i128,
+}
+
+impl Display for OverflowError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "adding {} to {} would overflow the counter",
+            self.delta, self.current
+        )
+    }
+}
+
+impl std::error::Error for OverflowError {}
+
+/// A 64-bit counter that can be shared between threads and that handles overflow according to
+/// its `OverflowPolicy`
+#[derive(Debug)]
+pub struct Counter {
+    /// the current value
+    value: AtomicI64,
+
+    /// how increments and decrements behave at the edges of the i64 range
+    policy: OverflowPolicy,
+}
+
+impl Counter {
+    /// create a new counter with the given starting value
+    pub fn new(initial: i64, policy: OverflowPolicy) -> Self {
+        Self {
+            value: AtomicI64::new(initial),
+            policy,
+        }
+    }
+
+    /// atomically retrieve the current value
+    pub fn fetch(&self) -> i64 {
+        self.value.load(Ordering::SeqCst)
+    }
+
+    /// atomically add `amount` to the counter, returning the new value
+    pub fn increment(&self, amount: i64) -> Result<i64, OverflowError> {
+        self.apply(i128::from(amount))
+    }
+
+    /// atomically subtract `amount` from the counter, returning the new value
+    pub fn decrement(&self, amount: i64) -> Result<i64, OverflowError> {
+        // negating i64::MIN doesn't fit in an i64, but it does in an i128
+        self.apply(-i128::from(amount))
+    }
+
+    /// atomically replace the counter's value with `value`
+    pub fn set(&self, value: i64) {
+        self.value.store(value, Ordering::SeqCst);
+    }
+
+    /// atomically replace the counter's value with `value`, returning the previous value
+    pub fn get_and_set(&self, value: i64) -> i64 {
+        self.value.swap(value, Ordering::SeqCst)
+    }
+
+    /// atomically replace the counter's value with `new`, but only if it currently holds
+    /// `expected`
+    ///
+    /// on success, the previous value (which is always `expected`) is returned as `Ok`. On
+    /// failure, the counter is left alone and the value that was actually observed is returned as
+    /// `Err`
+    pub fn compare_and_swap(&self, expected: i64, new: i64) -> Result<i64, i64> {
+        self.value
+            .compare_exchange(expected, new, Ordering::SeqCst, Ordering::SeqCst)
+    }
+
+    /// atomically add `delta` to a counter holding 0 or more, stopping at 0 rather than going
+    /// below it, and return the new value
+    ///
+    /// this ignores the overflow policy. If the counter is negative, or adding `delta` would take
+    /// it past `i64::MAX`, the counter is left alone and the value it holds is returned as `Err`
+    pub fn add_unsigned(&self, delta: i64) -> Result<i64, i64> {
+        self.value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                next_unsigned(current, delta)
+            })
+            // like in apply(), recompute the new value from the one we replaced
+            .map(|previous| next_unsigned(previous, delta).unwrap())
+    }
+
+    /// add `delta` to the counter according to the overflow policy and return the new value
+    fn apply(&self, delta: i128) -> Result<i64, OverflowError> {
+        if self.policy == OverflowPolicy::Wrap {
+            // wrapping is what the hardware does anyway, so a single fetch_add is all we need.
+            // truncating the i128 back down to an i64 gives us the same wrapped delta
+            let delta = delta as i64;
+            let previous = self.value.fetch_add(delta, Ordering::SeqCst);
+            return Ok(previous.wrapping_add(delta));
+        }
+
+        // the other policies need to look at the current value before deciding what the new one
+        // should be. fetch_update is a compare-and-swap loop: it reads the current value, calls
+        // our closure to compute the new value, and only stores it if nobody else changed the
+        // counter in the meantime. If someone did, it re-reads and tries again.
+        let result = self
+            .value
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |current| {
+                next_value(current, delta, self.policy)
+            });
+
+        match result {
+            // fetch_update hands back the value from *before* the update; recompute the new one
+            Ok(previous) => Ok(next_value(previous, delta, self.policy).unwrap()),
+            Err(current) => Err(OverflowError { current, delta }),
+        }
+    }
+}
+
+/// compute `current + delta` for `add_unsigned`; `None` means the update must be rejected
+fn next_unsigned(current: i64, delta: i64) -> Option<i64> {
+    if current < 0 {
+        return None;
+    }
+
+    // with current >= 0, the sum can only overflow upwards
+    current.checked_add(delta).map(|sum| sum.max(0))
+}
+
+/// compute `current + delta` under `policy`; `None` means the update must be rejected
+fn next_value(current: i64, delta: i128, policy: OverflowPolicy) -> Option<i64> {
+    // an i128 can hold the sum of any i64 and any delta we produce, so this can't overflow
+    let sum = i128::from(current) + delta;
+
+    match policy {
+        OverflowPolicy::Wrap => Some(sum as i64),
+        OverflowPolicy::Saturate => {
+            Some(sum.clamp(i128::from(i64::MIN), i128::from(i64::MAX)) as i64)
+        }
+        OverflowPolicy::Reject => i64::try_from(sum).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_goes_around_at_both_edges() {
+        let counter = Counter::new(i64::MAX, OverflowPolicy::Wrap);
+        assert_eq!(counter.increment(1), Ok(i64::MIN));
+        assert_eq!(counter.decrement(1), Ok(i64::MAX));
+        assert_eq!(counter.fetch(), i64::MAX);
+
+        let counter = Counter::new(i64::MIN, OverflowPolicy::Wrap);
+        assert_eq!(counter.decrement(2), Ok(i64::MAX - 1));
+    }
+
+    #[test]
+    fn wrap_handles_decrementing_by_i64_min() {
+        // -i64::MIN doesn't fit in an i64; adding it wraps around to adding i64::MIN
+        let counter = Counter::new(0, OverflowPolicy::Wrap);
+        assert_eq!(counter.decrement(i64::MIN), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn saturate_stops_at_both_edges() {
+        let counter = Counter::new(i64::MAX - 1, OverflowPolicy::Saturate);
+        assert_eq!(counter.increment(5), Ok(i64::MAX));
+        assert_eq!(counter.increment(1), Ok(i64::MAX));
+
+        let counter = Counter::new(i64::MIN + 1, OverflowPolicy::Saturate);
+        assert_eq!(counter.decrement(5), Ok(i64::MIN));
+        assert_eq!(counter.decrement(i64::MIN), Ok(0));
+
+        let counter = Counter::new(i64::MIN, OverflowPolicy::Saturate);
+        assert_eq!(counter.increment(i64::MIN), Ok(i64::MIN));
+    }
+
+    #[test]
+    fn reject_leaves_the_counter_alone() {
+        let counter = Counter::new(i64::MAX - 1, OverflowPolicy::Reject);
+        assert_eq!(counter.increment(1), Ok(i64::MAX));
+        assert_eq!(
+            counter.increment(1),
+            Err(OverflowError {
+                current: i64::MAX,
+                delta: 1
+            })
+        );
+        assert_eq!(counter.fetch(), i64::MAX);
+
+        let counter = Counter::new(0, OverflowPolicy::Reject);
+        assert_eq!(
+            counter.decrement(i64::MIN),
+            Err(OverflowError {
+                current: 0,
+                delta: -i128::from(i64::MIN)
+            })
+        );
+        assert_eq!(counter.decrement(i64::MAX), Ok(-i64::MAX));
+        assert_eq!(counter.decrement(1), Ok(i64::MIN));
+        assert!(counter.decrement(1).is_err());
+        assert_eq!(counter.fetch(), i64::MIN);
+    }
+
+    #[test]
+    fn every_policy_agrees_away_from_the_edges() {
+        for policy in &[
+            OverflowPolicy::Wrap,
+            OverflowPolicy::Saturate,
+            OverflowPolicy::Reject,
+        ] {
+            let counter = Counter::new(10, *policy);
+            assert_eq!(counter.increment(5), Ok(15));
+            assert_eq!(counter.decrement(20), Ok(-5));
+            assert_eq!(counter.increment(-5), Ok(-10));
+        }
+    }
+
+    #[test]
+    fn compare_and_swap_only_swaps_the_expected_value() {
+        let counter = Counter::new(5, OverflowPolicy::Reject);
+
+        assert_eq!(counter.compare_and_swap(4, 9), Err(5));
+        assert_eq!(counter.fetch(), 5);
+        assert_eq!(counter.compare_and_swap(5, 9), Ok(5));
+        assert_eq!(counter.fetch(), 9);
+    }
+
+    #[test]
+    fn set_and_get_and_set_ignore_the_policy() {
+        let counter = Counter::new(1, OverflowPolicy::Reject);
+
+        counter.set(i64::MIN);
+        assert_eq!(counter.get_and_set(i64::MAX), i64::MIN);
+        assert_eq!(counter.fetch(), i64::MAX);
+    }
+
+    #[test]
+    fn policies_parse_from_their_names() {
+        assert_eq!("wrap".parse(), Ok(OverflowPolicy::Wrap));
+        assert_eq!("Saturate".parse(), Ok(OverflowPolicy::Saturate));
+        assert_eq!("REJECT".parse(), Ok(OverflowPolicy::Reject));
+        assert!("clamp".parse::<OverflowPolicy>().is_err());
+    }
+}
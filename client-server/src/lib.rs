@@ -1 +1,8 @@
+pub mod client;
+pub mod counter;
+pub mod persistence;
 pub mod protocol;
+pub mod store;
+pub mod syntax;
+
+pub use client::Client;
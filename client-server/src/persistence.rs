@@ -0,0 +1,428 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::Command;
+
+/// name of the write-ahead log inside the data directory
+const WAL_FILE: &str = "wal.log";
+
+/// name of the snapshot inside the data directory
+const SNAPSHOT_FILE: &str = "snapshot.json";
+
+/// When appended log entries are forced out of the OS's page cache and onto disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// fsync after every entry; nothing acknowledged to a client is ever lost, but every
+    /// mutating command waits on the disk
+    Always,
+
+    /// fsync once per second, from a background thread (see `Store::open`); a crash can lose
+    /// up to a second of acknowledged commands
+    EverySecond,
+
+    /// never fsync, leave it up to the OS; fastest, but a power loss can lose a lot
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySecond),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!(
+                "unknown fsync policy '{}', expected one of always, everysec, never",
+                s
+            )),
+        }
+    }
+}
+
+/// A single line of the write-ahead log
+#[derive(Serialize, Deserialize, Debug)]
+struct WalEntry {
+    /// monotonically increasing sequence number, used to skip entries already in the snapshot
+    seq: u64,
+
+    /// the mutating command that was applied
+    cmd: Command,
+}
+
+/// On-disk representation of every counter at a single point in time
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Snapshot {
+    /// sequence number of the last log entry reflected in `counters`
+    pub last_seq: u64,
+
+    /// counter name -> value
+    pub counters: HashMap<String, i64>,
+}
+
+/// Everything recovered from a data directory at startup
+#[derive(Debug)]
+pub struct Recovered {
+    /// the most recent snapshot, or an empty one if none has been taken yet
+    pub snapshot: Snapshot,
+
+    /// commands logged after the snapshot was taken, in the order they were applied
+    pub commands: Vec<Command>,
+}
+
+/// Durable storage for a `Store`: a write-ahead log of mutating commands plus periodic snapshots
+/// that let the log be truncated
+#[derive(Debug)]
+pub struct Persistence {
+    /// directory holding the log and snapshot
+    dir: PathBuf,
+
+    /// open handle to the write-ahead log, positioned at its end
+    wal: File,
+
+    /// when to fsync the log
+    fsync: FsyncPolicy,
+
+    /// true when entries have been logged since the last fsync
+    unsynced: bool,
+
+    /// sequence number of the most recently logged entry
+    seq: u64,
+
+    /// take a snapshot after this many entries have been logged since the last one
+    snapshot_every: u64,
+
+    /// entries logged since the last snapshot
+    since_snapshot: u64,
+}
+
+impl Persistence {
+    /// open (or create) the data directory at `dir` and read back whatever state it holds
+    ///
+    /// the caller is expected to rebuild its state from the returned `Recovered` before logging
+    /// any new entries
+    pub fn open(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        snapshot_every: u64,
+    ) -> io::Result<(Persistence, Recovered)> {
+        fs::create_dir_all(dir)?;
+
+        let snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        let (commands, seq, intact) = read_wal(&dir.join(WAL_FILE), snapshot.last_seq)?;
+
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+
+        // the log may have just been created; make sure its directory entry is on disk too
+        sync_dir(dir)?;
+
+        // cut off the torn entry read_wal stopped at, if any. Left in place, the next entry
+        // would be appended to the end of the torn line, and every later replay would fail
+        // there
+        if wal.metadata()?.len() > intact {
+            eprintln!(
+                "truncating write-ahead log to its last complete entry, at byte {}",
+                intact
+            );
+            wal.set_len(intact)?;
+            wal.sync_all()?;
+        }
+
+        let persistence = Persistence {
+            dir: dir.to_path_buf(),
+            wal,
+            fsync,
+            unsynced: false,
+            seq,
+            snapshot_every,
+            // anything left in the log counts towards the next snapshot
+            since_snapshot: commands.len() as u64,
+        };
+
+        Ok((persistence, Recovered { snapshot, commands }))
+    }
+
+    /// append `cmd` to the log, syncing according to the fsync policy
+    pub fn append(&mut self, cmd: &Command) -> io::Result<()> {
+        let entry = WalEntry {
+            seq: self.seq + 1,
+            cmd: cmd.clone(),
+        };
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        // a single write per entry means a crash leaves at most one torn line at the very end of
+        // the log, which read_wal knows to ignore
+        self.wal.write_all(&line)?;
+
+        self.seq += 1;
+        self.since_snapshot += 1;
+        self.unsynced = true;
+
+        // under EverySecond, the syncing is left to a timer; see Store::open
+        if self.fsync == FsyncPolicy::Always {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+
+    /// true when enough entries have been logged that it's time to take a snapshot
+    pub fn snapshot_due(&self) -> bool {
+        self.snapshot_every > 0 && self.since_snapshot >= self.snapshot_every
+    }
+
+    /// write `counters` out as the new snapshot and empty the log
+    ///
+    /// `counters` must reflect every entry logged so far, which the caller guarantees by not
+    /// applying any new mutations while this runs
+    pub fn snapshot(&mut self, counters: HashMap<String, i64>) -> io::Result<()> {
+        let snapshot = Snapshot {
+            last_seq: self.seq,
+            counters,
+        };
+
+        // write to a temporary file and rename it over the old snapshot. rename is atomic, so
+        // a crash part way through leaves either the old snapshot or the new one, never half of
+        // each
+        let tmp = self.dir.join(format!("{}.tmp", SNAPSHOT_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&snapshot)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(SNAPSHOT_FILE))?;
+
+        // the rename is a change to the directory, not to either file, and it isn't durable
+        // until the directory itself is synced. Without this, a crash could keep the emptied log
+        // below but lose the rename, bringing back the old snapshot with nothing to replay
+        sync_dir(&self.dir)?;
+
+        // the log can only be emptied once the snapshot is safely on disk. If we crash between
+        // the rename and here, the old entries are still in the log, but their sequence numbers
+        // are <= last_seq, so they're skipped during replay instead of being applied twice
+        self.wal.set_len(0)?;
+        self.wal.sync_all()?;
+        self.unsynced = false;
+        self.since_snapshot = 0;
+
+        Ok(())
+    }
+
+    /// force everything logged so far onto disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.wal.sync_data()?;
+        self.unsynced = false;
+
+        Ok(())
+    }
+
+    /// like `sync`, but skips the fsync when nothing has been logged since the last one
+    pub fn sync_if_needed(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// force the entries of directory `dir` (which files it holds, and under what names) onto disk
+///
+/// on unix that's an fsync of the directory itself. Windows won't open a directory as a
+/// `File`, so there it's left up to the OS
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// read every entry from the log at `path` whose sequence number is greater than `after`
+///
+/// returns the commands, the highest sequence number seen, and the length in bytes of the part
+/// of the log that holds complete entries. An entry that's complete but unreadable is an error
+fn read_wal(path: &Path, after: u64) -> io::Result<(Vec<Command>, u64, u64)> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), after, 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut reader = BufReader::new(file);
+    let mut commands = Vec::new();
+    let mut seq = after;
+    let mut intact = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+
+        // a crash in the middle of an append leaves a partial last line, without its newline.
+        // read_until only stops without one at the end of the file, so this is the last line;
+        // everything before it is intact, so stop here rather than refusing to start
+        let json = match line.strip_suffix(b"\n") {
+            Some(json) => json,
+            None => {
+                eprintln!(
+                    "ignoring incomplete write-ahead log entry at byte {}, left by a crash",
+                    intact
+                );
+                break;
+            }
+        };
+
+        // a complete line that doesn't parse is another matter: no crash leaves that behind,
+        // and the entries after it are as valid as the ones before. Dropping them would quietly
+        // lose acknowledged writes, so it's up to a person to look at the log
+        let entry: WalEntry = serde_json::from_slice(json).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is corrupt at byte {}: {}; refusing to start",
+                    path.display(),
+                    intact,
+                    e
+                ),
+            )
+        })?;
+
+        intact += line.len() as u64;
+
+        if entry.seq > after {
+            seq = entry.seq;
+            commands.push(entry.cmd);
+        }
+    }
+
+    Ok((commands, seq, intact))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    /// a fresh, empty data directory for the test called `name`
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("persistence-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    /// the names and amounts of the Increments in `commands`
+    fn increments(commands: &[Command]) -> Vec<(&str, i64)> {
+        commands
+            .iter()
+            .map(|cmd| match cmd {
+                Command::Increment(name, amount) => (name.as_str(), *amount),
+                other => panic!("unexpected command: {:?}", other),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recovers_from_torn_tail_and_keeps_appending() {
+        let dir = data_dir("torn-tail");
+
+        let (mut persistence, _) = Persistence::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        persistence
+            .append(&Command::Increment("a".to_string(), 5))
+            .unwrap();
+        drop(persistence);
+
+        // what a crash part way through writing an entry leaves behind
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        wal.write_all(br#"{"seq":2,"cmd":{"Incr"#).unwrap();
+        drop(wal);
+
+        let (mut persistence, recovered) = Persistence::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(increments(&recovered.commands), [("a", 5)]);
+
+        persistence
+            .append(&Command::Increment("a".to_string(), 7))
+            .unwrap();
+        drop(persistence);
+
+        // the entry logged after the recovery has to survive the next one
+        let (_, recovered) = Persistence::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        assert_eq!(increments(&recovered.commands), [("a", 5), ("a", 7)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_to_start_with_a_corrupt_entry_before_the_end() {
+        let dir = data_dir("corrupt");
+
+        let (mut persistence, _) = Persistence::open(&dir, FsyncPolicy::Always, 0).unwrap();
+        persistence
+            .append(&Command::Increment("a".to_string(), 5))
+            .unwrap();
+        drop(persistence);
+
+        // a complete line that isn't an entry, followed by one that is
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        wal.write_all(b"garbage\n{\"seq\":3,\"cmd\":{\"Increment\":[\"a\",7]}}\n")
+            .unwrap();
+        drop(wal);
+
+        let before = fs::read(dir.join(WAL_FILE)).unwrap();
+        let err = Persistence::open(&dir, FsyncPolicy::Always, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // nothing was cut off, so whoever repairs the log still has every entry
+        assert_eq!(fs::read(dir.join(WAL_FILE)).unwrap(), before);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_entries_already_in_the_snapshot() {
+        let dir = data_dir("snapshot");
+
+        let (mut persistence, _) = Persistence::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        persistence
+            .append(&Command::Increment("a".to_string(), 1))
+            .unwrap();
+        persistence
+            .snapshot(vec![("a".to_string(), 1)].into_iter().collect())
+            .unwrap();
+        persistence
+            .append(&Command::Increment("a".to_string(), 2))
+            .unwrap();
+        drop(persistence);
+
+        let (_, recovered) = Persistence::open(&dir, FsyncPolicy::Never, 0).unwrap();
+        assert_eq!(recovered.snapshot.counters.get("a"), Some(&1));
+        assert_eq!(increments(&recovered.commands), [("a", 2)]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,28 +1,214 @@
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
-use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::io::{self, ErrorKind, Read, Write};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-/// size of buffer used for tcp stream read/write operations
-const BUF_SIZE: usize = 1024;
+/// number of bytes used for the length header that precedes every frame on the wire
+const HEADER_SIZE: usize = 4;
+
+/// default upper bound (in bytes) on the size of a single frame's payload; anything larger is
+/// rejected before we allocate space for it
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Everything that can go wrong while moving a `Message` across the wire
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// the underlying socket returned an error
+    Io(io::Error),
+
+    /// a `Message` couldn't be serialized into json
+    Encode(serde_json::Error),
+
+    /// a complete frame arrived, but its payload isn't a valid `Message`
+    Decode(serde_json::Error),
+
+    /// a complete, well-formed frame arrived, but it names a command the server doesn't know
+    UnsupportedCommand(String),
+
+    /// the frame's length header exceeds the maximum frame size
+    Oversize { size: usize, max: usize },
+
+    /// the peer closed the connection before a complete frame arrived
+    Eof,
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Io(e) => write!(f, "i/o error: {}", e),
+            ProtocolError::Encode(e) => write!(f, "couldn't encode message: {}", e),
+            ProtocolError::Decode(e) => write!(f, "couldn't decode message: {}", e),
+            ProtocolError::Oversize { size, max } => write!(
+                f,
+                "frame of {} bytes exceeds maximum of {} bytes",
+                size, max
+            ),
+            ProtocolError::UnsupportedCommand(name) => write!(f, "unsupported command: {}", name),
+            ProtocolError::Eof => write!(f, "connection closed by peer"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            ProtocolError::Encode(e) | ProtocolError::Decode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    /// allows the `?` operator to convert socket errors into a `ProtocolError`. A read that hits
+    /// the end of the stream is reported as `Eof` so callers can tell a closed connection apart
+    /// from a broken one
+    fn from(e: io::Error) -> Self {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            ProtocolError::Eof
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
+
+/// name of the counter used when a client doesn't care which counter it talks to
+pub const DEFAULT_COUNTER: &str = "default";
 
 /// Possible commands the server can execute
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Command {
     /// simple server ping, if alive, server will respond with pong
     Ping,
 
-    /// increment the server's counter by the given amount
-    Increment(i32),
+    /// increment the named counter by the given amount, creating the counter if needed
+    Increment(String, i64),
+
+    /// decrement the named counter by the given amount, creating the counter if needed
+    Decrement(String, i64),
+
+    /// like `Increment`, but replies with the counter's new value
+    IncrementAndGet(String, i64),
+
+    /// like `Decrement`, but replies with the counter's new value
+    DecrementAndGet(String, i64),
+
+    /// add the given amount (negative to subtract) to the named counter the way memcached's
+    /// incr and decr do: the counter has to exist already and hold 0 or more, and it stops at 0
+    /// instead of going below it. Replies with the counter's new value
+    AddUnsigned(String, i64),
+
+    /// get the current value of the named counter
+    Fetch(String),
+
+    /// set the named counter to the given value, creating the counter if needed
+    Set(String, i64),
+
+    /// set the named counter to `new`, but only if its current value is `expected`. A counter
+    /// that doesn't exist yet is treated as holding 0
+    CompareAndSwap {
+        name: String,
+        expected: i64,
+        new: i64,
+    },
+
+    /// set the named counter to the given value and reply with the value it held before
+    GetAndSet(String, i64),
+
+    /// get the current values of several counters at once
+    FetchMany(Vec<String>),
 
-    /// decrement the server's counter by the given amount
-    Decrement(i32),
+    /// get the names of every counter on the server
+    List,
 
-    /// get the current value of the server's counter
-    Fetch,
+    /// remove the named counter
+    Delete(String),
+
+    /// execute several commands as one atomic unit: either all of them take effect or none do,
+    /// and no other client's commands are interleaved with them. A compare-and-swap that doesn't
+    /// find the value it expected counts as a failure, so one can guard the rest of the batch
+    Batch(Vec<Command>),
+
+    /// ask the server to shut down gracefully; only honored when the server was started with
+    /// remote shutdown enabled
+    Shutdown,
 }
 
-/// Simple message protocol definition
+impl Command {
+    /// true if executing this command can change the server's state
+    pub fn is_mutating(&self) -> bool {
+        match self {
+            Command::Ping
+            | Command::Fetch(_)
+            | Command::FetchMany(_)
+            | Command::List
+            | Command::Shutdown => false,
+            Command::Increment(..)
+            | Command::Decrement(..)
+            | Command::IncrementAndGet(..)
+            | Command::DecrementAndGet(..)
+            | Command::AddUnsigned(..)
+            | Command::Set(..)
+            | Command::CompareAndSwap { .. }
+            | Command::GetAndSet(..)
+            | Command::Delete(_) => true,
+            Command::Batch(cmds) => cmds.iter().any(Command::is_mutating),
+        }
+    }
+
+    /// true if sending this command twice has the same effect, and gets the same reply, as
+    /// sending it once
+    ///
+    /// only idempotent commands are safe to retry when the connection breaks after the command
+    /// was sent, since there's no telling whether the server executed it
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Command::Ping
+            | Command::Fetch(_)
+            | Command::FetchMany(_)
+            | Command::List
+            | Command::Set(..)
+            | Command::Shutdown => true,
+            // a second Delete or CompareAndSwap changes nothing, but it gets a different reply
+            // than the first one did
+            Command::Increment(..)
+            | Command::Decrement(..)
+            | Command::IncrementAndGet(..)
+            | Command::DecrementAndGet(..)
+            | Command::AddUnsigned(..)
+            | Command::GetAndSet(..)
+            | Command::CompareAndSwap { .. }
+            | Command::Delete(_) => false,
+            Command::Batch(cmds) => cmds.iter().all(Command::is_idempotent),
+        }
+    }
+
+    /// names of every `Command` variant, as they appear on the wire
+    ///
+    /// used to tell a command we've never heard of apart from a known command with bad arguments;
+    /// keep this in sync with the enum above
+    pub const VARIANTS: &'static [&'static str] = &[
+        "Ping",
+        "Increment",
+        "Decrement",
+        "IncrementAndGet",
+        "DecrementAndGet",
+        "AddUnsigned",
+        "Fetch",
+        "Set",
+        "CompareAndSwap",
+        "GetAndSet",
+        "FetchMany",
+        "List",
+        "Delete",
+        "Batch",
+        "Shutdown",
+    ];
+}
+
+/// Simple message protocol definition; clients send a `Message`, the server answers with a
+/// `Response`
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Message {
     /// optional command, when present dictates server actions
@@ -32,52 +218,485 @@ pub struct Message {
     pub body: Option<String>,
 }
 
+/// Machine-readable reason attached to an error `Response`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// the frame arrived intact, but its payload isn't a valid `Message`
+    Malformed,
+
+    /// the frame is larger than the server is willing to accept
+    FrameTooLarge,
+
+    /// the message didn't include a command
+    MissingCommand,
+
+    /// the message's command isn't one the server knows how to execute
+    UnsupportedCommand,
+
+    /// the update would push the counter past the edge of its range and the server is
+    /// configured to reject such updates
+    Overflow,
+
+    /// the command refers to a counter that doesn't exist
+    NotFound,
+
+    /// the command only works on counters holding 0 or more, and this one is negative
+    Negative,
+
+    /// one of the commands in a batch failed (or was a compare-and-swap that didn't match), so
+    /// none of them were applied
+    BatchAborted,
+
+    /// the server couldn't write the change to durable storage
+    Storage,
+
+    /// the server is already serving as many connections as it's allowed to
+    ServerBusy,
+
+    /// the server is shutting down and isn't starting any new requests
+    ShuttingDown,
+
+    /// the server is configured not to allow this command
+    Forbidden,
+}
+
+/// The server's reply to a single `Message`
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    /// reply to `Command::Ping`
+    Pong,
+
+    /// the command was carried out and has nothing else to report
+    Ok,
+
+    /// the counter's value, as seen by the command
+    Value(i64),
+
+    /// reply to `Command::FetchMany`, one entry per requested counter in the order they were
+    /// requested; counters that don't exist are `None`
+    Values(Vec<Option<i64>>),
+
+    /// reply to `Command::List`
+    Names(Vec<String>),
+
+    /// reply to a `Command::CompareAndSwap` whose expected value didn't match; carries the
+    /// value that was actually observed so the client can decide whether to retry
+    CasFailed { observed: i64 },
+
+    /// reply to a `Command::Batch` that was applied; one entry per command, in order
+    Batch(Vec<Response>),
+
+    /// the command couldn't be carried out
+    Error { code: ErrorCode, message: String },
+}
+
 impl Message {
-    /// Serialize and return the current Message
-    pub fn to_stream(&self, stream: &mut TcpStream) {
-        let serialized = serde_json::to_string(&self).unwrap();
+    /// Serialize the current Message and send it as a single frame
+    ///
+    /// `stream` can be anything that implements `Write`: a `TcpStream`, a `UnixStream`, or a
+    /// `Vec<u8>` to collect the frame in memory
+    pub fn to_stream<W: Write>(&self, stream: &mut W) -> Result<(), ProtocolError> {
+        write_frame(stream, self)
+    }
 
-        stream
-            .write_all(serialized.as_bytes())
-            .expect("Couldn't send via socket");
+    /// Read a single frame from `stream` and attempt to deserialize it, allowing payloads of up
+    /// to `DEFAULT_MAX_FRAME_SIZE` bytes. If deserialization succeeds, the parsed `Message` is
+    /// returned to the caller.
+    ///
+    /// `stream` can be anything that implements `Read`. Since `&TcpStream` and `&UnixStream`
+    /// both do, a shared reference to a socket is enough
+    pub fn from_stream<R: Read>(stream: R) -> Result<Message, ProtocolError> {
+        Message::from_stream_with_limit(stream, DEFAULT_MAX_FRAME_SIZE)
     }
 
-    /// Read up to `BUF_SIZE` bytes from `stream` and attempt to deserialize. If deserialization
-    /// succeeds, the parsed `Message` is returned to the caller.
-    pub fn from_stream(mut stream: &TcpStream) -> Message {
-        // scratch buffer, defined on the stack
-        let mut buf = [0; BUF_SIZE];
+    /// Read a single frame from `stream` and attempt to deserialize it. Frames whose length
+    /// header claims more than `max_frame_size` bytes are rejected.
+    pub fn from_stream_with_limit<R: Read>(
+        mut stream: R,
+        max_frame_size: usize,
+    ) -> Result<Message, ProtocolError> {
+        let buf = read_frame_bytes(&mut stream, max_frame_size)?;
 
-        // read up to BUF_SIZE bytes from the established connection
-        let bytes_read = stream.read(&mut buf).expect("Couldn't read from socket");
+        Message::from_slice(&buf)
+    }
 
-        // grab a slice of bytes that should be json, discarding the remaining null bytes as they
-        // jack up the deserialization call below
-        let maybe_json = &buf[..bytes_read];
+    /// async counterpart of `from_stream_with_limit`, for use with tokio streams
+    pub async fn from_async_stream_with_limit<R>(
+        stream: &mut R,
+        max_frame_size: usize,
+    ) -> Result<Message, ProtocolError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let buf = read_frame_bytes_async(stream, max_frame_size).await?;
 
-        // attempt to deserialize the bytes into a Message object and return it to the caller
-        serde_json::from_slice(maybe_json).expect("Couldn't deserialize")
+        Message::from_slice(&buf)
+    }
+
+    /// Attempt to deserialize a `Message` from a frame's payload
+    ///
+    /// When the payload fails to parse, the error distinguishes between json that isn't a
+    /// `Message` at all (`Decode`) and a `Message` whose command names something that isn't a
+    /// `Command` variant (`UnsupportedCommand`)
+    pub fn from_slice(buf: &[u8]) -> Result<Message, ProtocolError> {
+        // first pass: is this json at all?
+        let value: serde_json::Value =
+            serde_json::from_slice(buf).map_err(ProtocolError::Decode)?;
+
+        // second pass: is it a Message?
+        let err = match Message::deserialize(&value) {
+            Ok(msg) => return Ok(msg),
+            Err(e) => e,
+        };
+
+        // externally tagged enums are serialized either as a bare string ("Ping") or as an
+        // object with a single key ({"Increment": 5}), either way that string/key is the name
+        // of the command the client wanted
+        let name = match value.get("cmd") {
+            Some(serde_json::Value::String(name)) => Some(name.as_str()),
+            Some(serde_json::Value::Object(map)) if map.len() == 1 => {
+                map.keys().next().map(String::as_str)
+            }
+            _ => None,
+        };
+
+        match name {
+            Some(name) if !Command::VARIANTS.contains(&name) => {
+                Err(ProtocolError::UnsupportedCommand(name.to_string()))
+            }
+            _ => Err(ProtocolError::Decode(err)),
+        }
     }
 }
 
+impl Response {
+    /// Serialize the current Response and send it as a single frame
+    pub fn to_stream<W: Write>(&self, stream: &mut W) -> Result<(), ProtocolError> {
+        write_frame(stream, self)
+    }
+
+    /// async counterpart of `to_stream`, for use with tokio streams
+    pub async fn to_async_stream<W>(&self, stream: &mut W) -> Result<(), ProtocolError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        stream.write_all(&encode_frame(self)?).await?;
+
+        Ok(())
+    }
+
+    /// Read a single frame from `stream` and attempt to deserialize it, allowing payloads of up
+    /// to `DEFAULT_MAX_FRAME_SIZE` bytes
+    pub fn from_stream<R: Read>(stream: R) -> Result<Response, ProtocolError> {
+        Response::from_stream_with_limit(stream, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Read a single frame from `stream` and attempt to deserialize it. Frames whose length
+    /// header claims more than `max_frame_size` bytes are rejected.
+    ///
+    /// a reply can be far larger than any command; a `List` names every counter the server has
+    pub fn from_stream_with_limit<R: Read>(
+        stream: R,
+        max_frame_size: usize,
+    ) -> Result<Response, ProtocolError> {
+        read_frame(stream, max_frame_size)
+    }
+
+    /// convenience constructor for `Response::Error`
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Response {
+        Response::Error {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// Serialize `value` and wrap it in a frame, ready to be written to a stream
+///
+/// A frame is a 4-byte, big-endian length header followed by exactly that many bytes of json
+fn encode_frame<T: Serialize>(value: &T) -> Result<Vec<u8>, ProtocolError> {
+    let serialized = serde_json::to_vec(value).map_err(ProtocolError::Encode)?;
+
+    // the length header is only 4 bytes wide, anything that doesn't fit can't be framed
+    if serialized.len() > u32::MAX as usize {
+        return Err(ProtocolError::Oversize {
+            size: serialized.len(),
+            max: u32::MAX as usize,
+        });
+    }
+
+    // the receiving side needs to know how many bytes make up the message before it starts
+    // reading, so the payload's length is sent first
+    let header = (serialized.len() as u32).to_be_bytes();
+
+    // header and payload go out in a single write; two small writes back to back can get
+    // stuck behind Nagle's algorithm and delayed acks, adding ~40ms to every request on a
+    // reused connection
+    let mut frame = Vec::with_capacity(HEADER_SIZE + serialized.len());
+    frame.extend_from_slice(&header);
+    frame.extend_from_slice(&serialized);
+
+    Ok(frame)
+}
+
+/// Parse a frame's length header, rejecting frames larger than `max_frame_size`
+fn decode_header(header: [u8; HEADER_SIZE], max_frame_size: usize) -> Result<usize, ProtocolError> {
+    let frame_size = u32::from_be_bytes(header) as usize;
+
+    // check the advertised size before allocating, otherwise a peer could ask us to reserve
+    // up to 4GiB of memory by sending a bogus header
+    if frame_size > max_frame_size {
+        return Err(ProtocolError::Oversize {
+            size: frame_size,
+            max: max_frame_size,
+        });
+    }
+
+    Ok(frame_size)
+}
+
+/// Serialize `value` and send it over `stream` as a single frame
+fn write_frame<T: Serialize, W: Write>(stream: &mut W, value: &T) -> Result<(), ProtocolError> {
+    stream.write_all(&encode_frame(value)?)?;
+
+    Ok(())
+}
+
+/// Read a single frame from `stream` and attempt to deserialize its payload into a `T`. Frames
+/// whose length header claims more than `max_frame_size` bytes are rejected.
+fn read_frame<T: DeserializeOwned, R: Read>(
+    mut stream: R,
+    max_frame_size: usize,
+) -> Result<T, ProtocolError> {
+    let buf = read_frame_bytes(&mut stream, max_frame_size)?;
+
+    // attempt to deserialize the bytes into the requested type and return it to the caller
+    serde_json::from_slice(&buf).map_err(ProtocolError::Decode)
+}
+
+/// Read a single frame from `stream` and return its raw payload. Frames whose length header
+/// claims more than `max_frame_size` bytes are rejected.
+fn read_frame_bytes<R: Read>(
+    stream: &mut R,
+    max_frame_size: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    // the length header is always the first thing sent
+    let mut header = [0; HEADER_SIZE];
+
+    // a single call to read may return fewer bytes than were sent (tcp is free to split a
+    // message across segments), read_exact keeps reading until the buffer is full
+    stream.read_exact(&mut header)?;
+
+    let frame_size = decode_header(header, max_frame_size)?;
+
+    // scratch buffer, defined on the heap and sized to hold exactly one frame
+    let mut buf = vec![0; frame_size];
+
+    stream.read_exact(&mut buf)?;
+
+    Ok(buf)
+}
+
+/// async counterpart of `read_frame_bytes`, for use with tokio streams
+async fn read_frame_bytes_async<R>(
+    stream: &mut R,
+    max_frame_size: usize,
+) -> Result<Vec<u8>, ProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; HEADER_SIZE];
+
+    // tokio's read_exact has the same keep-reading-until-full behavior as std's
+    stream.read_exact(&mut header).await?;
+
+    let frame_size = decode_header(header, max_frame_size)?;
+
+    let mut buf = vec![0; frame_size];
+
+    stream.read_exact(&mut buf).await?;
+
+    Ok(buf)
+}
+
 impl Display for Message {
     /// allow for easy printing of either Message::body or Message::cmd, depending on which is
     /// set to an actual value
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         // rhs values can be full blown expressions with blocks. our variable will be assigned based
-        // on the result of the if/else statement (whether or not we set the `body` field of our
-        // `Message`. Both arms of the match must 'return' the same type to be assigned
-        let pretty = if self.body.is_some() {
+        // on the result of the match expression (whether or not we set the `body` field of our
+        // `Message`). All arms of the match must 'return' the same type to be assigned
+        let pretty = match (&self.body, &self.cmd) {
             // this message's .body member is Some("..."), so we'll return the inner string to the
             // 'pretty' variable assignment
-            self.body.as_ref().unwrap().to_string()
-        } else {
+            (Some(body), _) => body.to_string(),
             // this message's .cmd member is Some(Command::...), so we'll return the inner Command
             // as a string to the 'pretty' variable assignment
-            format!("{:?}", self.cmd.as_ref().unwrap())
+            (None, Some(cmd)) => format!("{:?}", cmd),
+            // a client is free to send a Message with neither field set
+            (None, None) => "<empty message>".to_string(),
         };
 
         // write 'pretty' into the supplied output stream: `f`
         write!(f, "{}", pretty)
     }
 }
+
+impl Display for Response {
+    /// print a Response the way a human would want to read it, i.e. `pong` or `42` instead of
+    /// `Pong` or `Value(42)`
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Response::Pong => write!(f, "pong"),
+            Response::Ok => write!(f, "success"),
+            Response::Value(value) => write!(f, "{}", value),
+            Response::Values(values) => {
+                let pretty: Vec<String> = values
+                    .iter()
+                    .map(|value| match value {
+                        Some(value) => value.to_string(),
+                        None => "<missing>".to_string(),
+                    })
+                    .collect();
+
+                write!(f, "[{}]", pretty.join(", "))
+            }
+            Response::Names(names) => write!(f, "[{}]", names.join(", ")),
+            Response::CasFailed { observed } => {
+                write!(f, "compare-and-swap failed; observed {}", observed)
+            }
+            Response::Batch(responses) => {
+                let pretty: Vec<String> = responses.iter().map(Response::to_string).collect();
+
+                write!(f, "[{}]", pretty.join(", "))
+            }
+            Response::Error { code, message } => write!(f, "error ({:?}): {}", code, message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// a frame with `payload` after a header that claims it's `size` bytes long
+    fn frame(size: u32, payload: &[u8]) -> Cursor<Vec<u8>> {
+        let mut frame = size.to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+
+        Cursor::new(frame)
+    }
+
+    #[test]
+    fn messages_survive_a_round_trip() {
+        let msg = Message {
+            cmd: Some(Command::Increment("visits".to_string(), 5)),
+            body: Some("hello".to_string()),
+        };
+
+        let mut buf = Vec::new();
+        msg.to_stream(&mut buf).unwrap();
+
+        // the header counts the payload, not itself
+        assert_eq!(
+            buf[..HEADER_SIZE],
+            ((buf.len() - HEADER_SIZE) as u32).to_be_bytes()
+        );
+
+        // several frames back to back are read one at a time
+        msg.to_stream(&mut buf).unwrap();
+        let mut stream = Cursor::new(buf);
+
+        for _ in 0..2 {
+            let read = Message::from_stream(&mut stream).unwrap();
+            assert_eq!(format!("{:?}", read), format!("{:?}", msg));
+        }
+
+        assert!(matches!(
+            Message::from_stream(&mut stream),
+            Err(ProtocolError::Eof)
+        ));
+    }
+
+    #[test]
+    fn responses_survive_a_round_trip() {
+        let mut buf = Vec::new();
+        Response::Values(vec![Some(1), None])
+            .to_stream(&mut buf)
+            .unwrap();
+
+        match Response::from_stream(Cursor::new(buf)).unwrap() {
+            Response::Values(values) => assert_eq!(values, [Some(1), None]),
+            other => panic!("expected Values, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn frames_over_the_limit_are_oversize() {
+        // only the header is there; the payload mustn't be waited for, or allocated
+        let result = Message::from_stream_with_limit(frame(11, b""), 10);
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Oversize { size: 11, max: 10 })
+        ));
+
+        let result = Message::from_stream(frame(u32::MAX, b""));
+        assert!(matches!(result, Err(ProtocolError::Oversize { .. })));
+
+        // right at the limit is fine
+        let result = Message::from_stream_with_limit(frame(2, b"{}"), 2);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn end_of_stream_before_or_within_the_header_is_eof() {
+        for input in [&[][..], &[0], &[0, 0, 0]] {
+            let result = Message::from_stream(Cursor::new(input));
+            assert!(matches!(result, Err(ProtocolError::Eof)), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn truncated_payloads_are_rejected() {
+        let result = Message::from_stream(frame(10, b"{\"cmd\""));
+        assert!(matches!(result, Err(ProtocolError::Eof)));
+
+        let result = Response::from_stream(frame(10, b"\"Pong\""));
+        assert!(matches!(result, Err(ProtocolError::Eof)));
+    }
+
+    #[test]
+    fn malformed_payloads_in_a_valid_frame_are_decode_errors() {
+        for payload in &[
+            &b"not json"[..],
+            b"{\"cmd\": ",
+            b"\xff\xfe",
+            b"[1, 2]",
+            b"{\"cmd\": {\"Increment\": \"five\"}}",
+            b"{\"cmd\": {\"Ping\": 1, \"List\": 2}}",
+        ] {
+            let result = Message::from_stream(frame(payload.len() as u32, payload));
+            assert!(
+                matches!(result, Err(ProtocolError::Decode(_))),
+                "{:?}",
+                String::from_utf8_lossy(payload)
+            );
+        }
+
+        let result = Response::from_stream(frame(8, b"not json"));
+        assert!(matches!(result, Err(ProtocolError::Decode(_))));
+    }
+
+    #[test]
+    fn unknown_commands_are_unsupported_rather_than_malformed() {
+        let payload = b"{\"cmd\": {\"Explode\": 1}}";
+        let result = Message::from_stream(frame(payload.len() as u32, payload));
+
+        match result {
+            Err(ProtocolError::UnsupportedCommand(name)) => assert_eq!(name, "Explode"),
+            other => panic!("expected UnsupportedCommand, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,709 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+use crate::counter::{Counter, OverflowError, OverflowPolicy};
+use crate::persistence::{FsyncPolicy, Persistence};
+use crate::protocol::{Command, ErrorCode, Response};
+
+/// A collection of independent, named counters that can be shared between threads
+///
+/// The map itself sits behind a `RwLock`, but the counters inside are atomics. That means the
+/// common case (updating or reading a counter that already exists) only needs a shared read
+/// lock, and any number of threads can hold one at the same time. The exclusive write lock is
+/// only taken to create or delete a counter, or to run a `Command::Batch`.
+///
+/// A store created with `Store::open` is durable: every mutating command that goes through
+/// `execute` is written to a log on disk, and the log is replayed when the store is reopened.
+#[derive(Debug)]
+pub struct Store {
+    /// counter name -> counter
+    counters: RwLock<HashMap<String, Counter>>,
+
+    /// overflow policy handed to every counter created by this store
+    policy: OverflowPolicy,
+
+    /// durable storage, when enabled. Every mutating command takes this lock for as long as it
+    /// takes to apply and log the command, which keeps the log in the same order as the changes
+    /// it describes. Shared with the thread that fsyncs the log under `FsyncPolicy::EverySecond`
+    persistence: Option<Arc<Mutex<Persistence>>>,
+
+    /// set once a change couldn't be written to disk; from then on, mutating commands are
+    /// refused rather than acknowledged without being durable
+    storage_failed: AtomicBool,
+}
+
+impl Store {
+    /// create an empty, in-memory store whose counters use the given overflow policy
+    pub fn new(policy: OverflowPolicy) -> Self {
+        Self {
+            counters: RwLock::new(HashMap::new()),
+            policy,
+            persistence: None,
+            storage_failed: AtomicBool::new(false),
+        }
+    }
+
+    /// create a durable store backed by the data directory at `dir`, restoring whatever state
+    /// the directory already holds
+    ///
+    /// `snapshot_every` is the number of logged commands after which a snapshot is taken and the
+    /// log is emptied; 0 disables snapshots
+    pub fn open(
+        policy: OverflowPolicy,
+        dir: &Path,
+        fsync: FsyncPolicy,
+        snapshot_every: u64,
+    ) -> io::Result<Self> {
+        let (persistence, recovered) = Persistence::open(dir, fsync, snapshot_every)?;
+
+        let mut store = Store::new(policy);
+
+        // start from the snapshot...
+        store.counters = RwLock::new(
+            recovered
+                .snapshot
+                .counters
+                .into_iter()
+                .map(|(name, value)| (name, Counter::new(value, policy)))
+                .collect(),
+        );
+
+        // ...then replay everything that happened after it. apply() doesn't log, which is what
+        // we want here; these commands are already in the log
+        for cmd in &recovered.commands {
+            store.apply(cmd);
+        }
+
+        let persistence = Arc::new(Mutex::new(persistence));
+
+        if fsync == FsyncPolicy::EverySecond {
+            sync_every_second(Arc::downgrade(&persistence));
+        }
+
+        store.persistence = Some(persistence);
+
+        Ok(store)
+    }
+
+    /// add `amount` to the named counter, creating it (starting at 0) if it doesn't exist yet
+    fn increment(&self, name: &str, amount: i64) -> Result<i64, OverflowError> {
+        self.with_counter(name, |counter| counter.increment(amount))
+    }
+
+    /// subtract `amount` from the named counter, creating it (starting at 0) if it doesn't exist
+    /// yet
+    fn decrement(&self, name: &str, amount: i64) -> Result<i64, OverflowError> {
+        self.with_counter(name, |counter| counter.decrement(amount))
+    }
+
+    /// see `Counter::add_unsigned`. Unlike the other updates, this one never creates the
+    /// counter; `None` means it doesn't exist
+    fn add_unsigned(&self, name: &str, delta: i64) -> Option<Result<i64, i64>> {
+        let counters = self.counters.read().expect("counter store lock poisoned");
+
+        counters
+            .get(name)
+            .map(|counter| counter.add_unsigned(delta))
+    }
+
+    /// set the named counter to `value`, creating it if it doesn't exist yet
+    fn set(&self, name: &str, value: i64) {
+        self.with_counter(name, |counter| counter.set(value))
+    }
+
+    /// set the named counter to `value` and return its previous value. A counter that doesn't
+    /// exist yet is created, and its previous value is reported as 0
+    fn get_and_set(&self, name: &str, value: i64) -> i64 {
+        self.with_counter(name, |counter| counter.get_and_set(value))
+    }
+
+    /// set the named counter to `new`, but only if it currently holds `expected`. A counter that
+    /// doesn't exist yet is created with a value of 0 before the comparison is made
+    ///
+    /// see `Counter::compare_and_swap` for the meaning of the return value
+    fn compare_and_swap(&self, name: &str, expected: i64, new: i64) -> Result<i64, i64> {
+        self.with_counter(name, |counter| counter.compare_and_swap(expected, new))
+    }
+
+    /// get the current value of the named counter, if it exists
+    pub fn fetch(&self, name: &str) -> Option<i64> {
+        let counters = self.counters.read().expect("counter store lock poisoned");
+
+        counters.get(name).map(Counter::fetch)
+    }
+
+    /// get the current values of several counters at once; missing counters are `None`
+    pub fn fetch_many(&self, names: &[String]) -> Vec<Option<i64>> {
+        // a single read lock for the whole batch means no counter can be created or deleted
+        // halfway through
+        let counters = self.counters.read().expect("counter store lock poisoned");
+
+        names
+            .iter()
+            .map(|name| counters.get(name).map(Counter::fetch))
+            .collect()
+    }
+
+    /// names of all counters currently in the store, in sorted order
+    pub fn list(&self) -> Vec<String> {
+        let counters = self.counters.read().expect("counter store lock poisoned");
+
+        let mut names: Vec<String> = counters.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// remove the named counter, returning its final value if it existed
+    fn delete(&self, name: &str) -> Option<i64> {
+        let mut counters = self.counters.write().expect("counter store lock poisoned");
+
+        counters.remove(name).map(|counter| counter.fetch())
+    }
+
+    /// current value of every counter in the store
+    pub fn values(&self) -> HashMap<String, i64> {
+        let counters = self.counters.read().expect("counter store lock poisoned");
+
+        counters
+            .iter()
+            .map(|(name, counter)| (name.clone(), counter.fetch()))
+            .collect()
+    }
+
+    /// force everything logged so far onto disk; a no-op for in-memory stores
+    ///
+    /// called on shutdown, so that changes logged since the last periodic fsync aren't lost
+    pub fn flush(&self) -> io::Result<()> {
+        match &self.persistence {
+            Some(persistence) => persistence
+                .lock()
+                .expect("persistence lock poisoned")
+                .sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// execute a single `Command` against the store and build the matching `Response`
+    ///
+    /// when the store is durable, commands that changed something are logged before the
+    /// response is returned
+    pub fn execute(&self, cmd: &Command) -> Response {
+        let persistence = match &self.persistence {
+            Some(persistence) if cmd.is_mutating() => persistence,
+            // read-only commands and in-memory stores don't need to touch the disk at all
+            _ => return self.apply(cmd),
+        };
+
+        let mut persistence = persistence.lock().expect("persistence lock poisoned");
+
+        if self.storage_failed.load(Ordering::SeqCst) {
+            return Response::error(
+                ErrorCode::Storage,
+                "an earlier change couldn't be saved to disk; refusing further changes",
+            );
+        }
+
+        let response = self.apply(cmd);
+
+        // errors and failed compare-and-swaps didn't change anything, so there's nothing to log
+        if let Response::Error { .. } | Response::CasFailed { .. } = response {
+            return response;
+        }
+
+        if let Err(e) = persistence.append(cmd) {
+            // the change is already applied in memory, but we can't promise it will survive a
+            // restart, so don't tell the client it succeeded
+            self.storage_failed.store(true, Ordering::SeqCst);
+
+            return Response::error(
+                ErrorCode::Storage,
+                format!("couldn't write to the write-ahead log: {}", e),
+            );
+        }
+
+        if persistence.snapshot_due() {
+            // holding the persistence lock means no other change can sneak in while we take the
+            // snapshot. a failed snapshot isn't fatal; the log still has everything
+            if let Err(e) = persistence.snapshot(self.values()) {
+                eprintln!("couldn't take a snapshot: {}", e);
+            }
+        }
+
+        response
+    }
+
+    /// execute a single `Command` against the in-memory counters only
+    fn apply(&self, cmd: &Command) -> Response {
+        match cmd {
+            Command::Ping => {
+                // simple ping/pong connectivity test
+                Response::Pong
+            }
+            Command::Increment(name, val) => {
+                // atomically add the given value to the counter
+                match self.increment(name, *val) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => overflow(e),
+                }
+            }
+            Command::Decrement(name, val) => {
+                // atomically subtract the given value from the counter
+                match self.decrement(name, *val) {
+                    Ok(_) => Response::Ok,
+                    Err(e) => overflow(e),
+                }
+            }
+            Command::IncrementAndGet(name, val) => match self.increment(name, *val) {
+                Ok(value) => Response::Value(value),
+                Err(e) => overflow(e),
+            },
+            Command::DecrementAndGet(name, val) => match self.decrement(name, *val) {
+                Ok(value) => Response::Value(value),
+                Err(e) => overflow(e),
+            },
+            Command::AddUnsigned(name, val) => match self.add_unsigned(name, *val) {
+                Some(Ok(value)) => Response::Value(value),
+                Some(Err(current)) if current < 0 => Response::error(
+                    ErrorCode::Negative,
+                    format!("counter '{}' holds {}, which is negative", name, current),
+                ),
+                Some(Err(current)) => overflow(OverflowError {
+                    current,
+                    delta: i128::from(*val),
+                }),
+                None => not_found(name),
+            },
+            Command::Set(name, val) => {
+                self.set(name, *val);
+                Response::Ok
+            }
+            Command::GetAndSet(name, val) => Response::Value(self.get_and_set(name, *val)),
+            Command::CompareAndSwap {
+                name,
+                expected,
+                new,
+            } => match self.compare_and_swap(name, *expected, *new) {
+                Ok(_) => Response::Ok,
+                Err(observed) => Response::CasFailed { observed },
+            },
+            Command::Fetch(name) => {
+                // atomically retrieve the current value and return it in the response
+                match self.fetch(name) {
+                    Some(value) => Response::Value(value),
+                    None => not_found(name),
+                }
+            }
+            Command::FetchMany(names) => Response::Values(self.fetch_many(names)),
+            Command::List => Response::Names(self.list()),
+            Command::Delete(name) => match self.delete(name) {
+                Some(_) => Response::Ok,
+                None => not_found(name),
+            },
+            Command::Batch(cmds) => self.execute_batch(cmds),
+            Command::Shutdown => {
+                // shutting down is the server's business, not the store's; the server handles
+                // it before a command ever gets here, so this is only reachable from a batch
+                Response::error(
+                    ErrorCode::UnsupportedCommand,
+                    "Shutdown can't be executed by the store",
+                )
+            }
+        }
+    }
+
+    /// execute every command in `cmds` as a single, all-or-nothing unit
+    ///
+    /// The store's write lock is held for the entire batch, so no other command can observe or
+    /// modify any counter until the batch is done. The commands run against a scratch copy of the
+    /// store; only if every one of them succeeds is the copy swapped in for the real thing. If
+    /// any command replies with an error, or is a compare-and-swap that doesn't find the value it
+    /// expected, the copy is thrown away and the store is left exactly as it was. That lets a
+    /// compare-and-swap guard the rest of the batch.
+    fn execute_batch(&self, cmds: &[Command]) -> Response {
+        // a nested batch would try to take the scratch store's write lock from inside itself;
+        // there's no good reason to nest them anyway, so refuse up front
+        if cmds.iter().any(|cmd| matches!(cmd, Command::Batch(_))) {
+            return Response::error(ErrorCode::Malformed, "batches can't be nested");
+        }
+
+        let mut counters = self.counters.write().expect("counter store lock poisoned");
+
+        // copying every counter is O(n) in the number of counters, which is the price we pay for
+        // keeping single commands lock-light. Batches are expected to be the rare case
+        let scratch = Store {
+            counters: RwLock::new(
+                counters
+                    .iter()
+                    .map(|(name, counter)| {
+                        (name.clone(), Counter::new(counter.fetch(), self.policy))
+                    })
+                    .collect(),
+            ),
+            policy: self.policy,
+            persistence: None,
+            storage_failed: AtomicBool::new(false),
+        };
+
+        let mut responses = Vec::with_capacity(cmds.len());
+
+        for (i, cmd) in cmds.iter().enumerate() {
+            let response = scratch.apply(cmd);
+
+            let failure = match &response {
+                Response::Error { code, message } => Some(format!("{:?}: {}", code, message)),
+                Response::CasFailed { observed } => {
+                    Some(format!("CasFailed: observed {}", observed))
+                }
+                _ => None,
+            };
+
+            if let Some(failure) = failure {
+                return Response::error(
+                    ErrorCode::BatchAborted,
+                    format!(
+                        "command {} ({:?}) failed with {}; no changes were made",
+                        i, cmd, failure
+                    ),
+                );
+            }
+
+            responses.push(response);
+        }
+
+        // every command succeeded, make the scratch copy the real thing
+        *counters = scratch
+            .counters
+            .into_inner()
+            .expect("counter store lock poisoned");
+
+        Response::Batch(responses)
+    }
+
+    /// run `op` against the named counter, creating the counter first if necessary
+    fn with_counter<F, T>(&self, name: &str, op: F) -> T
+    where
+        F: Fn(&Counter) -> T,
+    {
+        // fast path: the counter already exists, so a shared read lock is all we need
+        {
+            let counters = self.counters.read().expect("counter store lock poisoned");
+
+            if let Some(counter) = counters.get(name) {
+                return op(counter);
+            }
+        } // read lock is released here, at the end of the block
+
+        // slow path: take the write lock and create the counter. another thread may have beaten
+        // us to it between releasing the read lock and acquiring the write lock, which is why
+        // entry() is used instead of a blind insert
+        let mut counters = self.counters.write().expect("counter store lock poisoned");
+
+        let counter = counters
+            .entry(name.to_string())
+            .or_insert_with(|| Counter::new(0, self.policy));
+
+        op(counter)
+    }
+}
+
+/// fsync the log once a second, in a thread of its own, for as long as the store is around
+///
+/// waiting for the next append to notice a second has gone by would leave the tail of a burst of
+/// changes unsynced for as long as things stay quiet afterwards. The thread only holds a weak
+/// reference, so it notices when the store is dropped and goes away too
+fn sync_every_second(persistence: Weak<Mutex<Persistence>>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+
+        let persistence = match persistence.upgrade() {
+            Some(persistence) => persistence,
+            None => return,
+        };
+
+        let mut persistence = persistence.lock().expect("persistence lock poisoned");
+
+        if let Err(e) = persistence.sync_if_needed() {
+            eprintln!("couldn't fsync the write-ahead log: {}", e);
+        }
+    });
+}
+
+/// error `Response` for a counter that doesn't exist
+fn not_found(name: &str) -> Response {
+    Response::error(ErrorCode::NotFound, format!("no counter named '{}'", name))
+}
+
+/// error `Response` for an update that was rejected by the overflow policy
+fn overflow(e: OverflowError) -> Response {
+    Response::error(ErrorCode::Overflow, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store_with(counters: &[(&str, i64)]) -> Store {
+        let store = Store::new(OverflowPolicy::Reject);
+
+        for (name, value) in counters {
+            store.set(name, *value);
+        }
+
+        store
+    }
+
+    fn compare_and_swap(name: &str, expected: i64, new: i64) -> Command {
+        Command::CompareAndSwap {
+            name: name.to_string(),
+            expected,
+            new,
+        }
+    }
+
+    #[test]
+    fn list_names_every_counter_in_order() {
+        let store = store_with(&[("b", 1), ("c", 2), ("a", 3)]);
+
+        match store.execute(&Command::List) {
+            Response::Names(names) => assert_eq!(names, ["a", "b", "c"]),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match Store::new(OverflowPolicy::Reject).execute(&Command::List) {
+            Response::Names(names) => assert!(names.is_empty()),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn delete_removes_a_counter() {
+        let store = store_with(&[("a", 1), ("b", 2)]);
+
+        assert!(matches!(
+            store.execute(&Command::Delete("a".to_string())),
+            Response::Ok
+        ));
+        assert_eq!(store.fetch("a"), None);
+        assert_eq!(store.list(), ["b"]);
+
+        // a deleted counter starts over from 0 the next time it's used
+        store.execute(&Command::Increment("a".to_string(), 5));
+        assert_eq!(store.fetch("a"), Some(5));
+    }
+
+    #[test]
+    fn deleting_a_missing_counter_is_not_found() {
+        let store = store_with(&[("a", 1)]);
+
+        assert!(matches!(
+            store.execute(&Command::Delete("missing".to_string())),
+            Response::Error {
+                code: ErrorCode::NotFound,
+                ..
+            }
+        ));
+        assert_eq!(store.list(), ["a"]);
+    }
+
+    #[test]
+    fn fetch_many_reports_missing_counters_in_place() {
+        let store = store_with(&[("a", 1), ("c", -3)]);
+
+        let names = ["a", "b", "c", "a"].iter().map(|name| name.to_string());
+
+        match store.execute(&Command::FetchMany(names.collect())) {
+            Response::Values(values) => assert_eq!(values, [Some(1), None, Some(-3), Some(1)]),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // only looking doesn't create anything
+        assert_eq!(store.fetch("b"), None);
+    }
+
+    #[test]
+    fn set_replaces_or_creates_a_counter() {
+        let store = store_with(&[("a", 1)]);
+
+        assert!(matches!(
+            store.execute(&Command::Set("a".to_string(), -7)),
+            Response::Ok
+        ));
+        assert!(matches!(
+            store.execute(&Command::Set("b".to_string(), 3)),
+            Response::Ok
+        ));
+        assert_eq!(store.fetch("a"), Some(-7));
+        assert_eq!(store.fetch("b"), Some(3));
+    }
+
+    #[test]
+    fn get_and_set_returns_the_previous_value() {
+        let store = store_with(&[("a", 1)]);
+
+        assert!(matches!(
+            store.execute(&Command::GetAndSet("a".to_string(), 10)),
+            Response::Value(1)
+        ));
+        assert_eq!(store.fetch("a"), Some(10));
+
+        // a counter that didn't exist had a value of 0
+        assert!(matches!(
+            store.execute(&Command::GetAndSet("b".to_string(), 4)),
+            Response::Value(0)
+        ));
+        assert_eq!(store.fetch("b"), Some(4));
+    }
+
+    #[test]
+    fn compare_and_swap_with_the_current_value_swaps() {
+        let store = store_with(&[("a", 5)]);
+
+        assert!(matches!(
+            store.execute(&compare_and_swap("a", 5, 6)),
+            Response::Ok
+        ));
+        assert_eq!(store.fetch("a"), Some(6));
+    }
+
+    #[test]
+    fn compare_and_swap_with_a_stale_value_reports_the_current_one() {
+        let store = store_with(&[("a", 5)]);
+
+        // the client read 4, but someone else has changed it since
+        assert!(matches!(
+            store.execute(&compare_and_swap("a", 4, 100)),
+            Response::CasFailed { observed: 5 }
+        ));
+        assert_eq!(store.fetch("a"), Some(5));
+
+        // retrying with the observed value goes through
+        assert!(matches!(
+            store.execute(&compare_and_swap("a", 5, 100)),
+            Response::Ok
+        ));
+        assert_eq!(store.fetch("a"), Some(100));
+    }
+
+    #[test]
+    fn compare_and_swap_treats_a_missing_counter_as_0() {
+        let store = store_with(&[]);
+
+        assert!(matches!(
+            store.execute(&compare_and_swap("a", 1, 2)),
+            Response::CasFailed { observed: 0 }
+        ));
+        assert!(matches!(
+            store.execute(&compare_and_swap("a", 0, 2)),
+            Response::Ok
+        ));
+        assert_eq!(store.fetch("a"), Some(2));
+    }
+
+    #[test]
+    fn batch_applies_every_command() {
+        let store = store_with(&[("a", 1)]);
+
+        let response = store.execute(&Command::Batch(vec![
+            Command::Increment("a".to_string(), 1),
+            Command::Increment("b".to_string(), 5),
+            Command::Fetch("a".to_string()),
+        ]));
+
+        match response {
+            Response::Batch(responses) => {
+                assert!(matches!(
+                    responses.as_slice(),
+                    [Response::Ok, Response::Ok, Response::Value(2)]
+                ))
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        assert_eq!(store.fetch("a"), Some(2));
+        assert_eq!(store.fetch("b"), Some(5));
+    }
+
+    #[test]
+    fn batch_rolls_back_on_error() {
+        let store = store_with(&[("a", 1), ("b", i64::MAX)]);
+
+        let response = store.execute(&Command::Batch(vec![
+            Command::Increment("a".to_string(), 1),
+            Command::Delete("a".to_string()),
+            Command::Increment("c".to_string(), 1),
+            Command::Increment("b".to_string(), 1),
+        ]));
+
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: ErrorCode::BatchAborted,
+                ..
+            }
+        ));
+
+        assert_eq!(store.fetch("a"), Some(1));
+        assert_eq!(store.fetch("b"), Some(i64::MAX));
+        assert_eq!(store.fetch("c"), None);
+    }
+
+    #[test]
+    fn batch_rolls_back_on_failed_compare_and_swap() {
+        let store = store_with(&[("g", 1)]);
+
+        let response = store.execute(&Command::Batch(vec![
+            Command::CompareAndSwap {
+                name: "g".to_string(),
+                expected: 99,
+                new: 0,
+            },
+            Command::Increment("g".to_string(), 100),
+        ]));
+
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: ErrorCode::BatchAborted,
+                ..
+            }
+        ));
+        assert_eq!(store.fetch("g"), Some(1));
+
+        // with the right expected value, the guarded update goes through
+        let response = store.execute(&Command::Batch(vec![
+            Command::CompareAndSwap {
+                name: "g".to_string(),
+                expected: 1,
+                new: 0,
+            },
+            Command::Increment("g".to_string(), 100),
+        ]));
+
+        assert!(matches!(response, Response::Batch(_)));
+        assert_eq!(store.fetch("g"), Some(100));
+    }
+
+    #[test]
+    fn batches_cant_be_nested() {
+        let store = store_with(&[]);
+
+        let response = store.execute(&Command::Batch(vec![
+            Command::Increment("a".to_string(), 1),
+            Command::Batch(vec![]),
+        ]));
+
+        assert!(matches!(
+            response,
+            Response::Error {
+                code: ErrorCode::Malformed,
+                ..
+            }
+        ));
+        assert_eq!(store.fetch("a"), None);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::protocol::{Command, DEFAULT_COUNTER};
+
+/// turn a command typed by a person, e.g. `inc visits 5`, into a `Command`
+///
+/// `verb` is the first word on the line and `args` are the rest; the verb isn't case sensitive.
+/// This is the syntax shared by the server's text protocol and the client's REPL:
+///
+/// ```text
+/// ping
+/// inc [name] <amount>     (also: incr, increment)
+/// dec [name] <amount>     (also: decr, decrement)
+/// set [name] <value>
+/// fetch [name]            (also: get)
+/// list
+/// del <name>              (also: delete)
+/// ```
+///
+/// the counter name is optional everywhere it'd be the only other argument, and defaults to
+/// `DEFAULT_COUNTER`.
+///
+/// returns `None` if `verb` isn't one of these, so each caller can add words of its own (like
+/// `quit`) and word its own "unknown command" error; `Some(Err(..))` means the verb was known but
+/// its arguments weren't right
+pub fn parse_command(verb: &str, args: &[&str]) -> Option<Result<Command, String>> {
+    let verb = verb.to_lowercase();
+
+    let cmd = match (verb.as_str(), args) {
+        ("ping", []) => Ok(Command::Ping),
+        ("inc", _) | ("incr", _) | ("increment", _) => {
+            name_and_number(&verb, args).map(|(name, amount)| Command::Increment(name, amount))
+        }
+        ("dec", _) | ("decr", _) | ("decrement", _) => {
+            name_and_number(&verb, args).map(|(name, amount)| Command::Decrement(name, amount))
+        }
+        ("set", _) => name_and_number(&verb, args).map(|(name, value)| Command::Set(name, value)),
+        ("fetch", []) | ("get", []) => Ok(Command::Fetch(DEFAULT_COUNTER.to_string())),
+        ("fetch", [name]) | ("get", [name]) => Ok(Command::Fetch(name.to_string())),
+        ("list", []) => Ok(Command::List),
+        ("del", [name]) | ("delete", [name]) => Ok(Command::Delete(name.to_string())),
+        ("ping", _) | ("list", _) => Err(format!("usage: {}", verb)),
+        ("fetch", _) | ("get", _) => Err(format!("usage: {} [name]", verb)),
+        ("del", _) | ("delete", _) => Err(format!("usage: {} <name>", verb)),
+        _ => return None,
+    };
+
+    Some(cmd)
+}
+
+/// parse the `[name] <number>` arguments shared by inc, dec and set
+fn name_and_number(verb: &str, args: &[&str]) -> Result<(String, i64), String> {
+    let (name, number) = match args {
+        [number] => (DEFAULT_COUNTER, number),
+        [name, number] => (*name, number),
+        _ => return Err(format!("usage: {} [name] <number>", verb)),
+    };
+
+    let number = number
+        .parse()
+        .map_err(|e| format!("'{}' isn't a number: {}", number, e))?;
+
+    Ok((name.to_string(), number))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// parse `line` and show the result with Debug, since Command can't be compared with ==
+    fn parse(line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        format!("{:?}", parse_command(words[0], &words[1..]))
+    }
+
+    #[test]
+    fn the_name_is_optional() {
+        assert_eq!(parse("inc 5"), r#"Some(Ok(Increment("default", 5)))"#);
+        assert_eq!(
+            parse("INCR visits 5"),
+            r#"Some(Ok(Increment("visits", 5)))"#
+        );
+        assert_eq!(
+            parse("decrement -3"),
+            r#"Some(Ok(Decrement("default", -3)))"#
+        );
+        assert_eq!(parse("set visits 0"), r#"Some(Ok(Set("visits", 0)))"#);
+        assert_eq!(parse("FETCH"), r#"Some(Ok(Fetch("default")))"#);
+        assert_eq!(parse("get visits"), r#"Some(Ok(Fetch("visits")))"#);
+        assert_eq!(parse("Del visits"), r#"Some(Ok(Delete("visits")))"#);
+        assert_eq!(parse("list"), "Some(Ok(List))");
+        assert_eq!(parse("ping"), "Some(Ok(Ping))");
+    }
+
+    #[test]
+    fn bad_arguments_are_errors() {
+        for line in &[
+            "inc",
+            "inc visits five",
+            "set a b 1",
+            "dec 99999999999999999999",
+            "fetch a b",
+            "del",
+            "ping pong",
+        ] {
+            assert!(parse(line).starts_with("Some(Err("), "{}", line);
+        }
+    }
+
+    #[test]
+    fn unknown_verbs_are_left_to_the_caller() {
+        assert_eq!(parse("quit"), "None");
+        assert_eq!(parse("help me"), "None");
+    }
+}